@@ -4,6 +4,7 @@
 //!
 //! ```yaml
 //! ---
+//! version: 2
 //! user:
 //!   name: name
 //!   email: email@email.com
@@ -21,22 +22,27 @@
 //!   - README.md
 //! packages:
 //!   homesync:
-//!     - $HOME/.homesync.yml
-//!     - $HOME/.config/homesync/homesync.yml
-//!     - $XDG_CONFIG_HOME/homesync.yml
-//!     - $XDG_CONFIG_HOME/homesync/homesync.yml
+//!     one_of:
+//!       - - $HOME/.homesync.yml
+//!         - $HOME/.config/homesync/homesync.yml
+//!         - $XDG_CONFIG_HOME/homesync.yml
+//!         - $XDG_CONFIG_HOME/homesync/homesync.yml
 //! ```
 
-use super::{path, path::ResPathBuf};
+use super::{copy, path, path::ResPathBuf, yaml};
 use paris::formatter::colorize_string;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
-use simplelog::{info, paris};
+use serde_yaml::{Mapping, Value};
+use simplelog::{info, paris, warn};
 use std::{
     collections::{BTreeMap, HashSet},
+    env,
     env::VarError,
     error, fmt, fs, io,
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 // ========================================
@@ -48,8 +54,16 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     IOError(io::Error),
+    IncludeCycle(PathBuf),
+    IncludeError(PathBuf, Box<Error>),
+    JsonError(serde_json::Error),
     MissingConfig,
+    MissingInclude(PathBuf),
+    PathError(path::Error),
     SerdeError(serde_yaml::Error),
+    TomlError(toml::de::Error),
+    TomlSerError(toml::ser::Error),
+    UnsupportedVersion(String),
     VarError(VarError),
 }
 
@@ -59,17 +73,36 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::JsonError(err)
+    }
+}
+
 impl From<serde_yaml::Error> for Error {
     fn from(err: serde_yaml::Error) -> Error {
         Error::SerdeError(err)
     }
 }
 
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Error {
+        Error::TomlError(err)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(err: toml::ser::Error) -> Error {
+        Error::TomlSerError(err)
+    }
+}
+
 impl From<path::Error> for Error {
     fn from(err: path::Error) -> Error {
         match err {
             path::Error::IOError(e) => Error::IOError(e),
             path::Error::VarError(e) => Error::VarError(e),
+            e => Error::PathError(e),
         }
     }
 }
@@ -80,12 +113,32 @@ impl From<VarError> for Error {
     }
 }
 
+impl From<yaml::Error> for Error {
+    fn from(err: yaml::Error) -> Error {
+        match err {
+            yaml::Error::SerdeError(e) => Error::SerdeError(e),
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::IOError(e) => write!(f, "{}", e),
+            Error::IncludeCycle(p) => write!(f, "{} includes itself", p.display()),
+            Error::IncludeError(p, e) => write!(f, "{}: {}", p.display(), e),
+            Error::JsonError(e) => write!(f, "{}", e),
             Error::MissingConfig => write!(f, "Could not find configuration file"),
+            Error::MissingInclude(p) => write!(f, "Could not find included file {}", p.display()),
+            Error::PathError(e) => write!(f, "{}", e),
             Error::SerdeError(e) => write!(f, "{}", e),
+            Error::TomlError(e) => write!(f, "{}", e),
+            Error::TomlSerError(e) => write!(f, "{}", e),
+            Error::UnsupportedVersion(v) => write!(
+                f,
+                "Unsupported config version {}. Expected a version between 1 and {}.",
+                v, VERSION
+            ),
             Error::VarError(e) => write!(f, "{}", e),
         }
     }
@@ -93,23 +146,98 @@ impl fmt::Display for Error {
 
 impl error::Error for Error {}
 
+impl Error {
+    /// The (1-indexed) line of the file a parsing error was found on, if known.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Error::IncludeError(_, e) => e.line(),
+            Error::JsonError(e) => Some(e.line()),
+            Error::SerdeError(e) => e.location().map(|l| l.line()),
+            Error::TomlError(e) => e.line_col().map(|(line, _)| line + 1),
+            _ => None,
+        }
+    }
+}
+
+// ========================================
+// Format
+// ========================================
+
+/// The formats a config may be written in, determined by file extension.
+/// Anything not ending in `.toml` or `.json` is considered YAML.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+
+    /// Deserializes the given contents.
+    pub fn parse<T: DeserializeOwned>(&self, contents: &str) -> Result<T> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::from_str(contents)?,
+            Format::Toml => toml::from_str(contents)?,
+            Format::Json => serde_json::from_str(contents)?,
+        })
+    }
+
+    /// Serializes the given value from scratch.
+    pub fn serialize(&self, value: &serde_yaml::Value) -> Result<String> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::to_string(value)?,
+            // TOML has no notion of null so leave these entries out entirely.
+            // Converting into a `toml::Value` first ensures tables are written
+            // after any plain values.
+            Format::Toml => toml::to_string_pretty(&toml::Value::try_from(without_nulls(value))?)?,
+            Format::Json => format!("{}\n", serde_json::to_string_pretty(value)?),
+        })
+    }
+}
+
+fn without_nulls(value: &serde_yaml::Value) -> serde_yaml::Value {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => serde_yaml::Value::Mapping(
+            mapping
+                .iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k.clone(), without_nulls(v)))
+                .collect(),
+        ),
+        serde_yaml::Value::Sequence(sequence) => {
+            serde_yaml::Value::Sequence(sequence.iter().map(without_nulls).collect())
+        }
+        v => v.clone(),
+    }
+}
+
 // ========================================
 // Config
 // ========================================
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The identity commits are made under.
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
 pub struct User {
     pub name: String,
     pub email: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The key pair used to authenticate with the remote repository.
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
 pub struct SSH {
     pub public: Option<PathBuf>,
     pub private: PathBuf,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
 pub struct Remote {
     pub name: String,
     pub branch: String,
@@ -122,31 +250,322 @@ impl Remote {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
 pub struct Repos {
+    /// Where the local repository lives. Created on the first pull if missing.
     pub local: PathBuf,
     pub remote: Remote,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The kinds of events homesync can notify about. Refer to
+/// [daemon::Notifier](../daemon/struct.Notifier.html).
+#[derive(
+    Clone, Copy, Debug, Deserialize, JsonSchema, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyEvent {
+    Stage,
+    Commit,
+    PushSuccess,
+    PushFailure,
+    Conflict,
+    Resolvable,
+    ConfigRemoved,
+}
+
+impl fmt::Display for NotifyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            NotifyEvent::Stage => "stage",
+            NotifyEvent::Commit => "commit",
+            NotifyEvent::PushSuccess => "push_success",
+            NotifyEvent::PushFailure => "push_failure",
+            NotifyEvent::Conflict => "conflict",
+            NotifyEvent::Resolvable => "resolvable",
+            NotifyEvent::ConfigRemoved => "config_removed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub struct Notify {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub events: Option<Vec<NotifyEvent>>,
+}
+
+impl Notify {
+    /// The events notified about if none are configured explicitly.
+    pub const DEFAULT_EVENTS: &'static [NotifyEvent] = &[
+        NotifyEvent::PushFailure,
+        NotifyEvent::Conflict,
+        NotifyEvent::ConfigRemoved,
+    ];
+}
+
+/// How a managed path that turns out to be a symlink is synced.
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Symlinks {
+    /// Sync the contents of the file the link points to.
+    #[default]
+    Follow,
+    /// Sync the link itself.
+    Link,
+    /// Refuse to sync the link.
+    Reject,
+}
+
+/// What happens once a managed file is deleted.
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnDelete {
+    /// Leave the copy in the local repository, and the file on other machines.
+    Keep,
+    /// Remove the copy from the local repository, and the file (keeping a
+    /// backup) on other machines.
+    Remove,
+    /// Ask before removing anything. Nothing is removed if running
+    /// non-interactively.
+    #[default]
+    Ask,
+}
+
+/// A named group of files synced together.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Package {
+    /// The files making up the package. May reference environment variables.
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    /// Groups of alternate locations of a single file. The local repository
+    /// keeps one copy of each, under the first location of the group, staged
+    /// from the first location that exists. It is applied to the first
+    /// location that exists on the machine, or else the first that expands.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub one_of: Vec<Vec<PathBuf>>,
+    /// Overrides the top-level `symlinks` policy for the paths of this package.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlinks: Option<Symlinks>,
+    /// Overrides the `symlinks` policy for individual paths of this package,
+    /// keyed by the path as listed (the first path of a `one_of` group).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub path_symlinks: BTreeMap<PathBuf, Symlinks>,
+    /// Whether the paths of this package (e.g. within `/etc`) may need root
+    /// to write to. Refer to [Config::escalate](struct.Config.html#structfield.escalate).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub privileged: bool,
+}
+
+/// Settings that only apply to a single machine.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Host {
+    /// Where files listed in packages (keyed by the path they are listed
+    /// under) live on this machine instead, e.g. for a differing home layout.
+    #[serde(default)]
+    pub paths: BTreeMap<PathBuf, PathBuf>,
+}
+
+/// Guards against syncing files that do not belong in git history, e.g. a
+/// cache accidentally added to a package.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LargeFiles {
+    /// Files larger than this many bytes are not staged. Refer to
+    /// [LargeFiles::DEFAULT_MAX_SIZE](struct.LargeFiles.html#associatedconstant.DEFAULT_MAX_SIZE).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Whether files that look binary are staged.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub allow_binary: bool,
+    /// Paths (as listed in packages) staged regardless of their size or
+    /// contents. Any path of a `one_of` group allows the whole group.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<PathBuf>,
+    /// A directory to keep the contents of allowed files over `max_size` in,
+    /// leaving only pointers to them in the local repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store: Option<PathBuf>,
+}
+
+impl LargeFiles {
+    /// The size limit if none is configured.
+    pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size.unwrap_or(LargeFiles::DEFAULT_MAX_SIZE)
+    }
+}
+
+/// The name of the current machine, as matched against the `hosts` of the
+/// config. Taken from `$HOMESYNC_HOST` if set, and the hostname otherwise.
+pub fn hostname() -> Option<String> {
+    if let Ok(host) = env::var("HOMESYNC_HOST") {
+        if !host.is_empty() {
+            return Some(host);
+        }
+    }
+    let mut buffer = [0u8; 256];
+    // SAFETY: The buffer is valid for writes of its full length.
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    if result != 0 {
+        return None;
+    }
+    // The name may be truncated without a terminating NUL.
+    let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    let host = String::from_utf8_lossy(&buffer[..len]).trim().to_owned();
+    Some(host).filter(|h| !h.is_empty())
+}
+
+impl Package {
+    /// Every path of the package, including each alternate in `one_of`.
+    pub fn all_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.paths.iter().chain(self.one_of.iter().flatten())
+    }
+
+    /// The paths of the package grouped with their alternates, i.e. each
+    /// entry of `paths` on its own followed by each `one_of` group. A group is
+    /// known by its first path, both in the local repository and in `hosts`.
+    pub fn groups(&self) -> impl Iterator<Item = &[PathBuf]> {
+        self.paths
+            .iter()
+            .map(std::slice::from_ref)
+            .chain(self.one_of.iter().map(Vec::as_slice))
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
 pub struct Config {
+    /// Refer to [VERSION](constant.VERSION.html).
+    #[schemars(description = "The version of the config format.")]
+    pub version: u64,
     pub user: User,
     pub ssh: SSH,
     pub repos: Repos,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<Notify>,
+    /// How managed symlinks are synced unless a package says otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlinks: Option<Symlinks>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include: Option<Vec<PathBuf>>,
     pub unmanaged: Option<HashSet<PathBuf>>,
-    pub packages: BTreeMap<String, Vec<PathBuf>>,
+    /// Whether files in the local repository no longer referenced by any
+    /// package are removed on stage without asking. The daemon never removes
+    /// them otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prune: Option<bool>,
+    /// How the deletion of a previously synced file is propagated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_delete: Option<OnDelete>,
+    /// The command prefixed to privileged writes, e.g. `[doas]`. Refer to
+    /// [Config::DEFAULT_ESCALATE](struct.Config.html#associatedconstant.DEFAULT_ESCALATE).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalate: Option<Vec<String>>,
+    /// Limits on which files are staged, keeping large and binary files out
+    /// of git history unless allowed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub large_files: Option<LargeFiles>,
+    /// Per machine settings, keyed by [hostname](fn.hostname.html).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, Host>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub packages: BTreeMap<String, Package>,
 }
 
 impl Config {
+    /// The command privileged writes go through if none is configured.
+    pub const DEFAULT_ESCALATE: &'static [&'static str] = &["sudo"];
+
     pub fn new(contents: &str) -> Result<Self> {
-        Ok(serde_yaml::from_str(&contents)?)
+        Config::parse(contents, Format::Yaml)
     }
+
+    /// Deserializes a config of the given format, migrating it to the current
+    /// [VERSION](constant.VERSION.html) if necessary.
+    pub fn parse(contents: &str, format: Format) -> Result<Self> {
+        Ok(parse_migrated(contents, format)?.0)
+    }
+}
+
+/// The parts of a config that may live in a file pulled in via `include`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fragment {
+    #[serde(default)]
+    pub version: Option<u64>,
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+    #[serde(default)]
+    pub unmanaged: HashSet<PathBuf>,
+    #[serde(default)]
+    pub packages: BTreeMap<String, Package>,
+}
+
+/// A file merged into the config via `include`.
+#[derive(Debug)]
+pub struct Included {
+    pub file: ResPathBuf,
+    pub fragment: Fragment,
+}
+
+/// An expanded `include` entry. The final component may contain `*` and `?`
+/// wildcards, e.g. `homesync.d/*.yml`.
+#[derive(Clone, Debug)]
+pub struct IncludePattern {
+    pub dir: PathBuf,
+    pub pattern: String,
+}
+
+impl IncludePattern {
+    fn has_wildcards(&self) -> bool {
+        self.pattern.contains(['*', '?'])
+    }
+
+    /// Whether the given path names a file this pattern includes.
+    pub fn matches(&self, path: &Path) -> bool {
+        path.parent() == Some(&self.dir)
+            && path
+                .file_name()
+                .is_some_and(|n| wildcard_match(&self.pattern, &n.to_string_lossy()))
+    }
+}
+
+// Hidden files are only matched by patterns explicitly starting with a `.`,
+// as in the shell.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    fn matches(p: &[char], n: &[char]) -> bool {
+        match (p.first(), n.first()) {
+            (None, None) => true,
+            (Some('*'), _) => matches(&p[1..], n) || (!n.is_empty() && matches(p, &n[1..])),
+            (Some('?'), Some(_)) => matches(&p[1..], &n[1..]),
+            (Some(a), Some(b)) if a == b => matches(&p[1..], &n[1..]),
+            _ => false,
+        }
+    }
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    matches(&p, &n)
 }
 
 #[derive(Debug)]
 pub struct PathConfig {
     pub homesync_yml: ResPathBuf,
     pub config: Config,
+    /// Files merged into `config` via `include`, in the order they were
+    /// merged.
+    pub includes: Vec<Included>,
+    /// The patterns `include` entries expanded to. Files created later on that
+    /// match one of these are picked up on reload.
+    pub include_patterns: Vec<IncludePattern>,
 }
 
 impl PathConfig {
@@ -154,33 +573,522 @@ impl PathConfig {
         PathConfig {
             homesync_yml: path.clone(),
             config,
+            includes: vec![],
+            include_patterns: vec![],
         }
     }
 
-    // TODO(jrpotter): Create backup file before overwriting.
-    pub fn write(&self) -> Result<()> {
-        let mut file = fs::File::create(&self.homesync_yml)?;
-        let serialized = serde_yaml::to_string(&self.config)?;
-        file.write_all(serialized.as_bytes())?;
+    /// Whether the given (resolved) path refers to the primary config, a file
+    /// it includes or a file it would include if it existed.
+    pub fn is_config_file(&self, path: &Path) -> bool {
+        self.homesync_yml.resolved() == path
+            || self.includes.iter().any(|i| i.file.resolved() == path)
+            || self.include_patterns.iter().any(|p| p.matches(path))
+    }
+
+    /// The settings of the current machine, if any.
+    pub fn host(&self) -> Option<&Host> {
+        hostname().and_then(|h| self.config.hosts.get(&h))
+    }
+
+    /// The included file a package's path was pulled in from, if any.
+    pub fn included_by(&self, package: &str, path: &Path) -> Option<&ResPathBuf> {
+        self.includes
+            .iter()
+            .find(|i| {
+                i.fragment
+                    .packages
+                    .get(package)
+                    .is_some_and(|p| p.all_paths().any(|p| p == path))
+            })
+            .map(|i| &i.file)
+    }
+
+    /// The [Symlinks](enum.Symlinks.html) policy applying to the given path
+    /// of a package, as listed (i.e. the first path of its group).
+    pub fn symlinks(&self, package: &str, path: &Path) -> Symlinks {
+        let package = self.config.packages.get(package);
+        package
+            .and_then(|p| p.path_symlinks.get(path).copied())
+            .or_else(|| package.and_then(|p| p.symlinks))
+            .or(self.config.symlinks)
+            .unwrap_or_default()
+    }
+
+    /// The command to escalate writes to the paths of the given package with,
+    /// or `None` if the package is not privileged or escalation is disabled
+    /// (by an empty `escalate`).
+    pub fn escalate(&self, package: &str) -> Option<Vec<String>> {
+        if !self.config.packages.get(package)?.privileged {
+            return None;
+        }
+        let command = match &self.config.escalate {
+            Some(command) => command.clone(),
+            None => Config::DEFAULT_ESCALATE
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        };
+        Some(command).filter(|c| !c.is_empty())
+    }
+
+    fn collect_includes(
+        &mut self,
+        base: &Path,
+        entries: &[PathBuf],
+        visiting: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        for entry in entries {
+            // Relative entries are relative to the including file.
+            let expanded = if path::is_anchored(entry) {
+                path::expand(entry)?
+            } else {
+                path::expand(&base.join(entry))?
+            };
+            let (dir, pattern) = match (expanded.parent(), expanded.file_name()) {
+                (Some(dir), Some(name)) => (dir, name.to_string_lossy().into_owned()),
+                _ => Err(Error::MissingInclude(entry.clone()))?,
+            };
+            let pattern = IncludePattern {
+                dir: dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf()),
+                pattern,
+            };
+            let wildcards = pattern.has_wildcards();
+            let mut files = vec![];
+            if wildcards {
+                if let Ok(read_dir) = fs::read_dir(&pattern.dir) {
+                    for dir_entry in read_dir {
+                        let file = dir_entry?.path();
+                        if file.is_file() && pattern.matches(&file) {
+                            files.push(file);
+                        }
+                    }
+                }
+                files.sort();
+            } else {
+                let mut file = pattern.dir.clone();
+                file.push(&pattern.pattern);
+                if !file.is_file() {
+                    Err(Error::MissingInclude(entry.clone()))?;
+                }
+                files.push(file);
+            }
+            self.include_patterns.push(pattern);
+
+            for file in files {
+                let resolved = path::resolve(&file)?;
+                if visiting.contains(resolved.resolved()) || self.homesync_yml == resolved {
+                    // Wildcards may well match the including files themselves,
+                    // e.g. `*.yml` next to the primary config.
+                    if wildcards {
+                        continue;
+                    }
+                    return Err(Error::IncludeCycle(file));
+                }
+                // A file included more than once is merged the first time.
+                if self.includes.iter().any(|i| i.file == resolved) {
+                    continue;
+                }
+                let contents = fs::read_to_string(&resolved)?;
+                let (fragment, version): (Fragment, u64) =
+                    parse_migrated(&contents, Format::of(&file))
+                        .map_err(|e| Error::IncludeError(file.clone(), Box::new(e)))?;
+                warn_outdated(&file, version);
+                let parent = resolved.resolved().parent().unwrap_or(Path::new("/"));
+                visiting.push(resolved.resolved().clone());
+                self.collect_includes(parent, &fragment.include, visiting)?;
+                visiting.pop();
+                self.includes.push(Included {
+                    file: resolved,
+                    fragment,
+                });
+            }
+        }
         Ok(())
     }
+
+    /// Merges all files found in `include` entries into the config.
+    ///
+    /// Included files are merged depth-first in the order they are listed,
+    /// with the files a wildcard matches sorted by name. The primary config is
+    /// merged last. Packages found in multiple files list the paths of each,
+    /// in merge order and without duplicates. `unmanaged` entries are unioned.
+    pub fn merge_includes(&mut self) -> Result<()> {
+        let entries = match &self.config.include {
+            Some(entries) => entries.clone(),
+            None => return Ok(()),
+        };
+        let base = self
+            .homesync_yml
+            .resolved()
+            .parent()
+            .unwrap_or(Path::new("/"))
+            .to_path_buf();
+        self.collect_includes(&base, &entries, &mut vec![])?;
+
+        let own = std::mem::take(&mut self.config.packages);
+        let mut packages: BTreeMap<String, Package> = BTreeMap::new();
+        let fragments = self.includes.iter().map(|i| &i.fragment.packages);
+        for source in fragments.chain([&own]) {
+            for (name, package) in source {
+                let merged = packages.entry(name.clone()).or_default();
+                // Later files take precedence, the primary config last of all.
+                merged.symlinks = package.symlinks.or(merged.symlinks);
+                merged.path_symlinks.extend(package.path_symlinks.clone());
+                merged.privileged |= package.privileged;
+                for path in &package.paths {
+                    if !merged.paths.contains(path) {
+                        merged.paths.push(path.clone());
+                    }
+                }
+                for group in &package.one_of {
+                    if !merged.one_of.contains(group) {
+                        merged.one_of.push(group.clone());
+                    }
+                }
+            }
+        }
+        self.config.packages = packages;
+        for included in &self.includes {
+            if !included.fragment.unmanaged.is_empty() {
+                self.config
+                    .unmanaged
+                    .get_or_insert_with(HashSet::new)
+                    .extend(included.fragment.unmanaged.iter().cloned());
+            }
+        }
+        Ok(())
+    }
+
+    // Removes anything pulled in from included files (and not also listed in
+    // the primary config) so that writing the config does not inline them.
+    fn own_value(&self, original: Option<&str>) -> Result<serde_yaml::Value> {
+        let mut value = serde_yaml::to_value(&self.config)?;
+        if self.includes.is_empty() {
+            return Ok(value);
+        }
+        let format = Format::of(self.homesync_yml.resolved());
+        let original: Option<Config> = original.and_then(|o| Config::parse(o, format).ok());
+        let mut packages = BTreeMap::new();
+        for (name, package) in &self.config.packages {
+            let listed = original.as_ref().and_then(|o| o.packages.get(name));
+            let own: Vec<PathBuf> = package
+                .paths
+                .iter()
+                .filter(|p| {
+                    listed.is_some_and(|l| l.paths.contains(p))
+                        || self.included_by(name, p).is_none()
+                })
+                .cloned()
+                .collect();
+            let one_of: Vec<Vec<PathBuf>> = package
+                .one_of
+                .iter()
+                .filter(|g| {
+                    listed.is_some_and(|l| l.one_of.contains(g))
+                        || !self.includes.iter().any(|i| {
+                            i.fragment
+                                .packages
+                                .get(name)
+                                .is_some_and(|p| p.one_of.contains(g))
+                        })
+                })
+                .cloned()
+                .collect();
+            if !own.is_empty() || !one_of.is_empty() || listed.is_some() {
+                packages.insert(
+                    name,
+                    Package {
+                        paths: own,
+                        one_of,
+                        symlinks: listed.map_or(package.symlinks, |l| l.symlinks),
+                        path_symlinks: listed.map_or_else(
+                            || package.path_symlinks.clone(),
+                            |l| l.path_symlinks.clone(),
+                        ),
+                        privileged: listed.map_or(package.privileged, |l| l.privileged),
+                    },
+                );
+            }
+        }
+        let listed = original.as_ref().and_then(|o| o.unmanaged.as_ref());
+        let unmanaged = self.config.unmanaged.as_ref().map(|u| {
+            u.iter()
+                .filter(|p| {
+                    listed.is_some_and(|l| l.contains(*p))
+                        || !self
+                            .includes
+                            .iter()
+                            .any(|i| i.fragment.unmanaged.contains(*p))
+                })
+                .collect::<Vec<_>>()
+        });
+        if let serde_yaml::Value::Mapping(mapping) = &mut value {
+            let packages = serde_yaml::to_value(packages)?;
+            mapping.insert("packages".into(), packages);
+            match unmanaged {
+                Some(u) if !u.is_empty() || listed.is_some() => {
+                    mapping.insert("unmanaged".into(), serde_yaml::to_value(u)?);
+                }
+                _ => {
+                    mapping.insert("unmanaged".into(), serde_yaml::Value::Null);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    /// Appends the specified paths to a package, creating the package if it
+    /// does not exist yet. Returns the paths that were actually added, i.e.
+    /// those not already tracked by the package.
+    pub fn add_paths(&mut self, package: &str, paths: &[PathBuf]) -> Vec<PathBuf> {
+        let entries = &mut self
+            .config
+            .packages
+            .entry(package.to_owned())
+            .or_default()
+            .paths;
+        let mut added = vec![];
+        for path in paths {
+            if !entries.contains(path) {
+                entries.push(path.clone());
+                added.push(path.clone());
+            }
+        }
+        added
+    }
+
+    /// Removes the specified paths from a package. If no paths are specified,
+    /// the package is removed altogether. A `one_of` group is removed if any of
+    /// its alternates is specified.
+    ///
+    /// Returns the paths that were removed, with each removed group standing
+    /// in as its first path (i.e. the path its file is kept under in the local
+    /// repository).
+    pub fn remove_paths(&mut self, package: &str, paths: Option<&[PathBuf]>) -> Vec<PathBuf> {
+        let (removed, groups) = match paths {
+            None => match self.config.packages.remove(package) {
+                Some(package) => (package.paths, package.one_of),
+                None => return vec![],
+            },
+            Some(paths) => {
+                let entries = match self.config.packages.get_mut(package) {
+                    Some(package) => package,
+                    None => return vec![],
+                };
+                let (removed, kept) = entries.paths.drain(..).partition(|p| paths.contains(p));
+                entries.paths = kept;
+                let (groups, kept) = entries
+                    .one_of
+                    .drain(..)
+                    .partition(|g: &Vec<PathBuf>| g.iter().any(|p| paths.contains(p)));
+                entries.one_of = kept;
+                (removed, groups)
+            }
+        };
+        removed
+            .into_iter()
+            .chain(groups.into_iter().filter_map(|g| g.into_iter().next()))
+            .collect()
+    }
+
+    /// Writes the config back to disk in the format its extension indicates.
+    /// For YAML, as many of the comments and as much of the formatting of the
+    /// existing file as possible are preserved. Refer to
+    /// [yaml::update](../yaml/fn.update.html).
+    ///
+    /// The file is replaced atomically and its previous contents are kept
+    /// alongside it with a `.bak` extension.
+    pub fn write(&self) -> Result<()> {
+        let target = self.homesync_yml.resolved();
+        let format = Format::of(target);
+        let serialized = match fs::read_to_string(target) {
+            Ok(original) => {
+                let value = self.own_value(Some(&original))?;
+                match format {
+                    Format::Yaml => yaml::update(&original, &value)?,
+                    _ => format.serialize(&value)?,
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                format.serialize(&self.own_value(None)?)?
+            }
+            Err(e) => Err(e)?,
+        };
+        replace_file(target, &serialized)
+    }
+}
+
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Atomically replaces the contents of a file (refer to
+/// [copy::write_atomic](../copy/fn.write_atomic.html)), keeping any previous
+/// contents alongside it with a `.bak` extension and the same permissions.
+pub fn replace_file(target: &Path, contents: &str) -> Result<()> {
+    match fs::read(target) {
+        Ok(previous) => {
+            let permissions = fs::metadata(target)?.permissions();
+            copy::write_atomic(&sibling(target, ".bak"), Some(permissions), |file| {
+                file.write_all(&previous)
+            })?
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => Err(e)?,
+    };
+    copy::write_atomic(target, None, |file| file.write_all(contents.as_bytes()))?;
+    Ok(())
+}
+
+// ========================================
+// Migration
+// ========================================
+
+/// The current version of the config format. Configs without a `version` are
+/// considered to be at version 1.
+pub const VERSION: u64 = 2;
+
+// The migration at index `i` upgrades a config from version `i + 1` to version
+// `i + 2`. Included files are migrated the same way.
+const MIGRATIONS: &[fn(&mut Mapping)] = &[packages_as_objects];
+
+// Version 1 listed the paths of each package directly rather than under
+// `paths`.
+fn packages_as_objects(config: &mut Mapping) {
+    if let Some(Value::Mapping(packages)) = config.get_mut(&Value::from("packages")) {
+        for (_, package) in packages.iter_mut() {
+            if let Value::Sequence(_) = package {
+                let mut object = Mapping::new();
+                object.insert(Value::from("paths"), std::mem::take(package));
+                *package = Value::Mapping(object);
+            }
+        }
+    }
+}
+
+/// Upgrades a config (or included file) to [VERSION](constant.VERSION.html)
+/// in place, returning the version it was originally at.
+pub fn migrate(value: &mut Value) -> Result<u64> {
+    let config = match value {
+        Value::Mapping(config) => config,
+        // Leave it to deserialization to report the problem.
+        _ => return Ok(VERSION),
+    };
+    let key = Value::from("version");
+    let version = match config.get(&key) {
+        None => 1,
+        Some(v) => match v.as_u64() {
+            Some(v) if (1..=VERSION).contains(&v) => v,
+            _ => Err(Error::UnsupportedVersion(
+                serde_yaml::to_string(v)?
+                    .trim_start_matches("---")
+                    .trim()
+                    .to_owned(),
+            ))?,
+        },
+    };
+    if version == VERSION {
+        return Ok(version);
+    }
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        migration(config);
+    }
+    // Keep the version at the top where it is easy to find.
+    let mut upgraded = Mapping::new();
+    upgraded.insert(key.clone(), Value::from(VERSION));
+    for (k, v) in config.iter() {
+        if *k != key {
+            upgraded.insert(k.clone(), v.clone());
+        }
+    }
+    *config = upgraded;
+    Ok(version)
+}
+
+/// Deserializes the given contents after migrating them, returning the version
+/// they were originally at.
+pub fn parse_migrated<T: DeserializeOwned>(contents: &str, format: Format) -> Result<(T, u64)> {
+    let mut value: Value = format.parse(contents)?;
+    let version = migrate(&mut value)?;
+    if version == VERSION {
+        // Deserializing the original contents keeps track of line numbers
+        // in any errors.
+        Ok((format.parse(contents)?, version))
+    } else {
+        Ok((serde_yaml::from_value(value)?, version))
+    }
+}
+
+fn warn_outdated(file: &Path, version: u64) {
+    if version < VERSION {
+        warn!(
+            "<cyan>{}</> is at version {} (the latest is {}). Run `<italic>homesync config \
+            migrate</>` to upgrade it.",
+            file.display(),
+            version,
+            VERSION
+        );
+    }
+}
+
+/// Upgrades the given config (or included) file to
+/// [VERSION](constant.VERSION.html), returning its original and upgraded
+/// contents if it was out of date. Nothing is written to disk.
+pub fn migrate_file(file: &Path) -> Result<Option<(String, String)>> {
+    let format = Format::of(file);
+    let original = fs::read_to_string(file)?;
+    let mut value: Value = format.parse(&original)?;
+    if migrate(&mut value)? == VERSION {
+        return Ok(None);
+    }
+    let migrated = match format {
+        Format::Yaml => yaml::update(&original, &value)?,
+        _ => format.serialize(&value)?,
+    };
+    Ok(Some((original, migrated)))
+}
+
+// ========================================
+// Schema
+// ========================================
+
+/// A JSON Schema describing the current [VERSION](constant.VERSION.html) of
+/// the config, e.g. for editors to validate and autocomplete against.
+pub fn schema() -> String {
+    let schema = schemars::schema_for!(Config);
+    // Serializing a schema cannot fail.
+    serde_json::to_string_pretty(&schema).unwrap()
 }
 
 // ========================================
 // Loading
 // ========================================
 
+/// The starter config written by [init](../init/fn.init.html).
+pub const TEMPLATE: &str = include_str!("../rsrc/template.yml");
+
 /// The paths our homesync configuration may live in, ordered by priority.
 pub const DEFAULT_PATHS: &[&str] = &[
     "$HOME/.homesync.yml",
+    "$HOME/.homesync.toml",
+    "$HOME/.homesync.json",
     "$HOME/.config/homesync/homesync.yml",
+    "$HOME/.config/homesync/homesync.toml",
+    "$HOME/.config/homesync/homesync.json",
     "$XDG_CONFIG_HOME/homesync.yml",
+    "$XDG_CONFIG_HOME/homesync.toml",
+    "$XDG_CONFIG_HOME/homesync.json",
     "$XDG_CONFIG_HOME/homesync/homesync.yml",
+    "$XDG_CONFIG_HOME/homesync/homesync.toml",
+    "$XDG_CONFIG_HOME/homesync/homesync.json",
 ];
 
 /// The paths our homesync configuration may live in, ordered by priority.
 pub fn default_paths() -> Vec<PathBuf> {
-    DEFAULT_PATHS.iter().map(|s| PathBuf::from(s)).collect()
+    DEFAULT_PATHS.iter().map(PathBuf::from).collect()
 }
 
 /// Reads in the homesync configuration file into a [PathConfig](struct.PathConfig.html)
@@ -193,8 +1101,12 @@ pub fn load(candidates: &Vec<ResPathBuf>) -> Result<PathConfig> {
             Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
             Err(err) => Err(Error::IOError(err))?,
             Ok(contents) => {
-                let config = Config::new(&contents)?;
-                return Ok(PathConfig::new(candidate, config));
+                let (config, version) =
+                    parse_migrated(&contents, Format::of(candidate.resolved()))?;
+                warn_outdated(candidate.unresolved(), version);
+                let mut pc = PathConfig::new(candidate, config);
+                pc.merge_includes()?;
+                return Ok(pc);
             }
         }
     }
@@ -232,3 +1144,290 @@ pub fn list_packages(pc: PathConfig) {
         println!("• {}", k);
     }
 }
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const PRIMARY: &str = "\
+version: 2
+user:
+  name: name
+  email: email@email.com
+ssh:
+  private: $HOME/.ssh/id_ed25519
+repos:
+  local: $HOME/.homesync
+  remote:
+    name: origin
+    branch: master
+    url: https://github.com/owner/repo.git
+include:
+  - team.yml
+  - homesync.d/*.yml
+unmanaged:
+  - LICENSE
+packages:
+  shell:
+    paths:
+      - $HOME/.bashrc
+";
+
+    fn paths(paths: &[&str]) -> Package {
+        Package {
+            paths: paths.iter().map(PathBuf::from).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]) -> ResPathBuf {
+        for (name, contents) in files {
+            let file = dir.join(name);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, contents).unwrap();
+        }
+        path::resolve(&dir.join(files[0].0)).unwrap()
+    }
+
+    #[test]
+    fn wildcard_match() {
+        assert!(super::wildcard_match("*.yml", "a.yml"));
+        assert!(super::wildcard_match("?.yml", "a.yml"));
+        assert!(!super::wildcard_match("*.yml", "a.yml.bak"));
+        assert!(!super::wildcard_match("*.yml", ".a.yml"));
+        assert!(super::wildcard_match(".*.yml", ".a.yml"));
+    }
+
+    #[test]
+    fn include() {
+        let temp_dir = TempDir::new().unwrap();
+        let primary = write_files(
+            temp_dir.path(),
+            &[
+                ("homesync.yml", PRIMARY),
+                (
+                    "team.yml",
+                    "unmanaged: [README.md]\npackages:\n  shell: [$HOME/.profile, $HOME/.bashrc]\n",
+                ),
+                ("homesync.d/b.yml", "packages:\n  vim: [$HOME/.vimrc]\n"),
+                ("homesync.d/a.yml", "include: [../nested.yml]\n"),
+                ("nested.yml", "packages:\n  vim: [$HOME/.gvimrc]\n"),
+            ],
+        );
+        let pc = load(&vec![primary]).unwrap();
+        assert_eq!(
+            pc.config.packages["shell"],
+            paths(&["$HOME/.profile", "$HOME/.bashrc"])
+        );
+        assert_eq!(
+            pc.config.packages["vim"],
+            paths(&["$HOME/.gvimrc", "$HOME/.vimrc"])
+        );
+        let unmanaged = pc.config.unmanaged.as_ref().unwrap();
+        assert!(unmanaged.contains(Path::new("LICENSE")));
+        assert!(unmanaged.contains(Path::new("README.md")));
+        assert!(pc.is_config_file(
+            &temp_dir
+                .path()
+                .canonicalize()
+                .unwrap()
+                .join("homesync.d/c.yml")
+        ));
+    }
+
+    #[test]
+    fn include_write() {
+        let temp_dir = TempDir::new().unwrap();
+        let primary = write_files(
+            temp_dir.path(),
+            &[
+                ("homesync.yml", PRIMARY),
+                ("team.yml", "packages:\n  vim: [$HOME/.vimrc]\n"),
+            ],
+        );
+        let mut pc = load(&vec![primary.clone()]).unwrap();
+        pc.add_paths("vim", &[PathBuf::from("$HOME/.gvimrc")]);
+        pc.write().unwrap();
+        let written = Config::new(&fs::read_to_string(&primary).unwrap()).unwrap();
+        assert_eq!(written.packages["vim"], paths(&["$HOME/.gvimrc"]));
+        assert_eq!(written.packages["shell"], paths(&["$HOME/.bashrc"]));
+        assert_eq!(written.unmanaged.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn remove_one_of() {
+        let temp_dir = TempDir::new().unwrap();
+        let primary = write_files(
+            temp_dir.path(),
+            &[
+                ("homesync.yml", PRIMARY),
+                (
+                    "team.yml",
+                    "packages:\n  vim:\n    one_of: [[$HOME/.vimrc, $HOME/.vim/vimrc]]\n",
+                ),
+            ],
+        );
+        let mut pc = load(&vec![primary]).unwrap();
+        let vimrc = PathBuf::from("$HOME/.vimrc");
+        assert!(pc
+            .included_by("vim", Path::new("$HOME/.vim/vimrc"))
+            .is_some());
+
+        // Groups are removed by any of their alternates, reported by their
+        // first path.
+        let group = vec![
+            PathBuf::from("$HOME/.gvimrc"),
+            PathBuf::from("$HOME/.vim/gvimrc"),
+        ];
+        pc.config
+            .packages
+            .get_mut("shell")
+            .unwrap()
+            .one_of
+            .push(group);
+        let removed = pc.remove_paths("shell", Some(&[PathBuf::from("$HOME/.vim/gvimrc")]));
+        assert_eq!(removed, vec![PathBuf::from("$HOME/.gvimrc")]);
+        assert!(pc.config.packages["shell"].one_of.is_empty());
+        assert_eq!(pc.remove_paths("vim", None), vec![vimrc]);
+    }
+
+    #[test]
+    fn symlinks() {
+        let temp_dir = TempDir::new().unwrap();
+        let primary = write_files(
+            temp_dir.path(),
+            &[
+                (
+                    "homesync.yml",
+                    &format!("{}    symlinks: reject\nsymlinks: link\n", PRIMARY),
+                ),
+                (
+                    "team.yml",
+                    "version: 2\npackages:\n  shell:\n    path_symlinks:\n      \
+                    $HOME/.bashrc: follow\n  vim:\n    paths: [$HOME/.vimrc]\n",
+                ),
+            ],
+        );
+        let pc = load(&vec![primary]).unwrap();
+        // Paths take precedence over their package, which takes precedence
+        // over the top-level policy.
+        let bashrc = Path::new("$HOME/.bashrc");
+        assert_eq!(pc.symlinks("shell", bashrc), Symlinks::Follow);
+        let profile = Path::new("$HOME/.profile");
+        assert_eq!(pc.symlinks("shell", profile), Symlinks::Reject);
+        let vimrc = Path::new("$HOME/.vimrc");
+        assert_eq!(pc.symlinks("vim", vimrc), Symlinks::Link);
+    }
+
+    #[test]
+    fn formats() {
+        let temp_dir = TempDir::new().unwrap();
+        let yaml: serde_yaml::Value = serde_yaml::from_str(PRIMARY).unwrap();
+        let toml = Format::Toml.serialize(&yaml).unwrap();
+        let json = Format::Json.serialize(&yaml).unwrap();
+        for (name, contents) in [("homesync.toml", toml), ("homesync.json", json)] {
+            let primary = write_files(
+                temp_dir.path(),
+                &[
+                    (name, &contents),
+                    ("team.yml", "packages:\n  vim: [$HOME/.vimrc]\n"),
+                ],
+            );
+            let mut pc = load(&vec![primary.clone()]).unwrap();
+            assert_eq!(pc.config.user.name, "name");
+            pc.add_paths("shell", &[PathBuf::from("$HOME/.profile")]);
+            pc.write().unwrap();
+            let written: Config = Format::of(primary.resolved())
+                .parse(&fs::read_to_string(&primary).unwrap())
+                .unwrap();
+            assert_eq!(written.packages.len(), 1);
+            assert_eq!(written.packages["shell"].paths.len(), 2);
+            assert_eq!(written.include.unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn include_cycle() {
+        let temp_dir = TempDir::new().unwrap();
+        let primary = write_files(
+            temp_dir.path(),
+            &[
+                ("homesync.yml", PRIMARY),
+                ("team.yml", "include: [homesync.d/a.yml]\n"),
+                ("homesync.d/a.yml", "include: [../team.yml]\n"),
+            ],
+        );
+        assert!(matches!(load(&vec![primary]), Err(Error::IncludeCycle(_))));
+    }
+
+    #[test]
+    fn include_wildcard_self() {
+        let temp_dir = TempDir::new().unwrap();
+        let primary = write_files(
+            temp_dir.path(),
+            &[
+                (
+                    "homesync.yml",
+                    &PRIMARY.replace("  - team.yml\n  - homesync.d/*.yml\n", "  - \"*.yml\"\n"),
+                ),
+                (
+                    "team.yml",
+                    "include: [\"*.yml\"]\npackages:\n  team:\n    paths: [$HOME/.team]\n",
+                ),
+            ],
+        );
+        let pc = load(&vec![primary]).unwrap();
+        assert_eq!(pc.includes.len(), 1);
+        assert!(pc.config.packages.contains_key("team"));
+    }
+
+    #[test]
+    fn migrate() {
+        let temp_dir = TempDir::new().unwrap();
+        let v1 = PRIMARY
+            .replace("version: 2\n", "")
+            .replace("    paths:\n", "")
+            .replace("      - ", "    - ")
+            .replace("unmanaged:", "# Kept as is.\nunmanaged:");
+        let primary = write_files(temp_dir.path(), &[("homesync.yml", &v1)]);
+        let (original, migrated) = migrate_file(primary.resolved()).unwrap().unwrap();
+        assert_eq!(original, v1);
+        assert!(migrated.starts_with("version: 2\n"));
+        assert!(migrated.contains("# Kept as is.\n"));
+        assert_eq!(
+            Config::new(&migrated).unwrap().packages,
+            Config::new(&v1).unwrap().packages
+        );
+        assert_eq!(
+            Config::new(&migrated).unwrap().packages["shell"],
+            paths(&["$HOME/.bashrc"])
+        );
+        fs::write(&primary, &migrated).unwrap();
+        assert!(migrate_file(primary.resolved()).unwrap().is_none());
+
+        let mut future: Value = serde_yaml::from_str("version: 99\n").unwrap();
+        assert!(matches!(
+            super::migrate(&mut future),
+            Err(Error::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn schema() {
+        let schema: serde_json::Value = serde_json::from_str(&super::schema()).unwrap();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        for contents in [PRIMARY, TEMPLATE, include_str!("../rsrc/example.yml")] {
+            let config: serde_json::Value = serde_yaml::from_str(contents).unwrap();
+            assert!(validator.is_valid(&config));
+        }
+        // Outdated configs only load by way of migration.
+        let v1: serde_json::Value =
+            serde_yaml::from_str(&PRIMARY.replace("version: 2\n", "")).unwrap();
+        assert!(!validator.is_valid(&v1));
+    }
+}
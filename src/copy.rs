@@ -1,14 +1,31 @@
 //! Utilities for traversing directories and copying files around.
 
-use super::{config::PathConfig, path, path::ResPathBuf};
-use git2::Repository;
+use super::{
+    config::{LargeFiles, OnDelete, PathConfig, Symlinks},
+    git, metadata,
+    metadata::{Manifest, Metadata, Tombstones},
+    path,
+    path::ResPathBuf,
+    prompt, state,
+    state::{Hashes, Tracked},
+    store,
+    store::{Pointer, Store},
+};
+use git2::{Oid, Repository};
 use simplelog::{info, paris, warn};
 use std::{
     collections::HashMap,
+    env,
     env::VarError,
-    error, fmt, fs, io,
+    error,
+    ffi::OsString,
+    fmt, fs, io,
+    io::Write,
+    os::unix,
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
     path::{Path, PathBuf},
-    result,
+    process, result,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 // ========================================
@@ -19,16 +36,44 @@ pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    GitError(git::Error),
     IOError(io::Error),
+    MetadataError(metadata::Error),
+    PathError(path::Error),
+    StateError(state::Error),
     VarError(VarError),
 }
 
+impl From<git::Error> for Error {
+    fn from(err: git::Error) -> Error {
+        Error::GitError(err)
+    }
+}
+
+impl From<git2::Error> for Error {
+    fn from(err: git2::Error) -> Error {
+        Error::GitError(git::Error::GitError(err))
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::IOError(err)
     }
 }
 
+impl From<metadata::Error> for Error {
+    fn from(err: metadata::Error) -> Error {
+        Error::MetadataError(err)
+    }
+}
+
+impl From<state::Error> for Error {
+    fn from(err: state::Error) -> Error {
+        Error::StateError(err)
+    }
+}
+
 impl From<VarError> for Error {
     fn from(err: VarError) -> Error {
         Error::VarError(err)
@@ -40,6 +85,7 @@ impl From<path::Error> for Error {
         match err {
             path::Error::IOError(e) => Error::IOError(e),
             path::Error::VarError(e) => Error::VarError(e),
+            e => Error::PathError(e),
         }
     }
 }
@@ -47,7 +93,11 @@ impl From<path::Error> for Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Error::GitError(e) => write!(f, "{}", e),
             Error::IOError(e) => write!(f, "{}", e),
+            Error::MetadataError(e) => write!(f, "{}", e),
+            Error::PathError(e) => write!(f, "{}", e),
+            Error::StateError(e) => write!(f, "{}", e),
             Error::VarError(e) => write!(f, "{}", e),
         }
     }
@@ -59,69 +109,134 @@ impl error::Error for Error {}
 // Application
 // ========================================
 
-fn apply_all(pc: &PathConfig) -> Result<()> {
+// Applies the files of the given package, or of every package if none is
+// given.
+fn apply_packages(pc: &PathConfig, package: Option<&str>) -> Result<()> {
     let workdir = get_workdir(pc)?;
+    let repo = Repository::open(workdir.resolved())?;
     let repo_files = walk_repo(workdir.as_ref())?;
-    let package_lookup = get_package_lookup(pc);
+    let mut package_lookup = get_package_lookup(pc);
+    if let Some(package) = package {
+        package_lookup.retain(|_, m| m.package == package);
+    }
+    let manifest = Manifest::load(workdir.resolved())?;
+    let tombstones = Tombstones::load(workdir.resolved())?;
+    let mut hashes = Hashes::load(workdir.resolved())?;
+    let mut tracked = Tracked::load(workdir.resolved())?;
+    let store = get_store(pc)?;
 
     for repo_file in &repo_files {
-        let path = match package_lookup.get(repo_file.unresolved()) {
-            Some(value) => value,
+        let managed = match package_lookup.get(repo_file.unresolved()) {
+            Some(managed) => managed,
             None => continue,
         };
-        if let Some(value) = path {
-            fs::copy(repo_file.resolved(), value.resolved())?;
-            info!(
-                "<bold>Copied:</> <cyan>{}</> from local repository.",
-                repo_file.unresolved().display(),
-            );
-        } else {
-            let expanded = match path::expand(repo_file.unresolved()) {
-                Ok(expanded) => expanded,
-                Err(_) => continue,
-            };
-            if let Some(p) = expanded.parent() {
-                fs::create_dir_all(p)?;
+        let target = match managed.target() {
+            Some(target) => target,
+            None => continue,
+        };
+        if managed.privileged {
+            if repo_file.resolved().is_symlink() {
+                warn!(
+                    "<bold>Skipped:</> <cyan>{}</> is a symlink, which cannot be applied \
+                    to privileged paths.",
+                    repo_file.unresolved().display()
+                );
+                continue;
+            }
+            if unreadable(&target) {
+                warn!(
+                    "<bold>Skipped:</> Cannot read <cyan>{}</> to compare against.",
+                    target.display()
+                );
+                continue;
+            }
+        }
+        // Files kept in the store are applied from there instead.
+        let src = match Pointer::read(repo_file.resolved())? {
+            Some(pointer) => match store.as_ref().map(|s| s.object(pointer.oid)) {
+                Some(object) if object.is_file() => object,
+                _ => {
+                    warn!(
+                        "<bold>Skipped:</> The contents of <cyan>{}</> are missing from the \
+                        store.",
+                        repo_file.unresolved().display()
+                    );
+                    continue;
+                }
+            },
+            None => repo_file.resolved().to_path_buf(),
+        };
+        if let Some(p) = target.parent() {
+            // Escalated writes create any missing directories themselves.
+            if let (Err(e), false) = (fs::create_dir_all(p), managed.privileged) {
+                Err(e)?;
             }
-            fs::copy(repo_file.resolved(), expanded)?;
+        }
+        let copied = apply_file(
+            &src,
+            &target,
+            managed.symlinks,
+            &mut hashes,
+            &repo,
+            tracked.base(repo_file.unresolved()),
+            managed.escalate.as_deref(),
+        )?;
+        if copied != Copied::Skipped {
+            // Only the mode of privileged paths is kept, by way of the
+            // escalated write.
+            if managed.privileged {
+                metadata::warn_exposed(&target);
+            } else {
+                restore_metadata(
+                    &manifest,
+                    repo_file.resolved(),
+                    repo_file.unresolved(),
+                    &target,
+                    copied == Copied::Written,
+                )?;
+            }
+            set_base(
+                &repo,
+                &mut hashes,
+                &mut tracked,
+                repo_file.unresolved(),
+                repo_file.resolved(),
+            )?;
+        }
+        if copied == Copied::Written {
             info!(
                 "<bold>Copied:</> <cyan>{}</> from local repository.",
                 repo_file.unresolved().display(),
             );
         }
     }
+    // Remove files deleted on other machines.
+    for path in tombstones.iter() {
+        if let Some(resolved) = package_lookup.get(path).and_then(|m| m.resolved.as_ref()) {
+            if fs::symlink_metadata(workdir.resolved().join(path)).is_err()
+                && apply_deletion(path, resolved.resolved(), pc.config.on_delete)?
+            {
+                tracked.remove(path);
+            }
+        }
+    }
+    hashes.save()?;
+    tracked.save()?;
 
     Ok(())
 }
 
-fn apply_one(pc: &PathConfig, package: &str) -> Result<()> {
-    let workdir = get_workdir(pc)?;
+fn apply_all(pc: &PathConfig) -> Result<()> {
+    apply_packages(pc, None)
+}
 
-    if let Some(paths) = pc.config.packages.get(package) {
-        for path in paths {
-            let mut repo_file = workdir.resolved().to_path_buf();
-            repo_file.push(path);
-            if !repo_file.exists() {
-                continue;
-            }
-            let expanded = match path::expand(path) {
-                Ok(expanded) => expanded,
-                Err(_) => continue,
-            };
-            if let Some(p) = expanded.parent() {
-                fs::create_dir_all(p)?;
-            }
-            fs::copy(repo_file, expanded)?;
-            info!(
-                "<bold>Copied:</> <cyan>{}</> from local repository.",
-                path.display()
-            );
-        }
+fn apply_one(pc: &PathConfig, package: &str) -> Result<()> {
+    if pc.config.packages.contains_key(package) {
+        apply_packages(pc, Some(package))
     } else {
         warn!("Could not find package <cyan>{}</> in config.", package);
+        Ok(())
     }
-
-    Ok(())
 }
 
 /// Copy files from the local repository to the corresponding file location on
@@ -143,52 +258,243 @@ pub fn apply(pc: &PathConfig, package: Option<&str>) -> Result<()> {
 // Staging
 // ========================================
 
+/// Paths in the local repository that are never considered stale, in addition
+/// to those listed in `unmanaged`. Directories cover everything within them.
+pub const PROTECTED: &[&str] = &[".gitignore", ".gitattributes", ".gitmodules", ".github"];
+
+/// What [stage](fn.stage.html) does with files in the local repository that are
+/// no longer referenced by any package.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prune {
+    /// Remove them without asking.
+    Remove,
+    /// Ask before removing them. Nothing is removed if running
+    /// non-interactively.
+    Confirm,
+    /// Only report them.
+    Keep,
+}
+
+// Files in the local repository not referenced by the config, nor protected or
+// explicitly unmanaged.
+fn find_stale(
+    pc: &PathConfig,
+    repo_files: &[ResPathBuf],
+    package_lookup: &HashMap<PathBuf, Managed>,
+) -> Vec<PathBuf> {
+    let mut stale: Vec<PathBuf> = repo_files
+        .iter()
+        .map(|f| f.unresolved())
+        .filter(|f| !package_lookup.contains_key(*f))
+        .filter(|f| !PROTECTED.iter().any(|p| f.starts_with(p)))
+        .filter(|f| !pc.config.unmanaged.as_ref().is_some_and(|m| m.contains(*f)))
+        .map(|f| f.to_path_buf())
+        .collect();
+    stale.sort();
+    stale
+}
+
 /// Finds all files specified in the homesync config and copies them (if they
 /// exist) into the local repo.
-pub fn stage(pc: &PathConfig) -> Result<()> {
+///
+/// Files in the local repository no longer referenced by the config are
+/// reported, and only removed as dictated by `prune`. This way a typo in the
+/// config does not silently wipe files from the repository. Managed files that
+/// were deleted from this machine since the last stage are instead handled as
+/// dictated by the `on_delete` policy of the config, leaving a tombstone for
+/// other machines to [apply](fn.apply.html).
+///
+/// Files over the size limit or that look binary are skipped with a warning
+/// unless allowed by the `large_files` section of the config. Allowed files
+/// over the size limit are moved into the [store](../store/index.html) if
+/// configured.
+///
+/// Returns whether anything in the local repository changed.
+pub fn stage(pc: &PathConfig, prune: Prune) -> Result<bool> {
     let workdir = get_workdir(pc)?;
+    let repo = Repository::open(workdir.resolved())?;
     let repo_files = walk_repo(workdir.as_ref())?;
     let package_lookup = get_package_lookup(pc);
 
-    // Find all files in our repository that are no longer being referenced in
-    // our primary config file. They should be removed from the repository.
-    for repo_file in &repo_files {
-        let unresolved = repo_file.unresolved();
-        if !package_lookup.contains_key(unresolved)
-            && !pc
-                .config
-                .unmanaged
-                .as_ref()
-                .map_or(false, |m| m.contains(unresolved))
-        {
-            fs::remove_file(repo_file.resolved())?;
+    let mut changed = false;
+    let stale = find_stale(pc, &repo_files, &package_lookup);
+    if !stale.is_empty() {
+        for path in &stale {
+            warn!(
+                "<bold>Stale:</> <cyan>{}</> is no longer referenced by any package.",
+                path.display()
+            );
         }
-        if let Some(p) = repo_file.resolved().parent() {
-            if p.read_dir()?.next().is_none() {
-                fs::remove_dir(p)?;
-            }
+        let remove = match prune {
+            Prune::Remove => true,
+            Prune::Confirm => prompt::confirm(
+                &format!("Remove {} file(s) from the local repository?", stale.len()),
+                false,
+            )?,
+            Prune::Keep => false,
+        };
+        if remove {
+            changed |= unstage(pc, &stale)?;
+        } else {
+            info!(
+                "Kept stale files. Run `<italic>homesync stage --prune</>` or list them \
+                under `<italic>unmanaged</>` to stop seeing this."
+            );
         }
     }
 
     // Find all resolvable files in our primary config and copy them into the
-    // repository.
-    for (key, value) in &package_lookup {
-        if let Some(value) = value {
-            let mut copy = workdir.resolved().to_path_buf();
-            copy.push(key);
+    // repository, skipping those whose contents are already up to date. Git
+    // does not track permissions (beyond the executable bit) so record them
+    // alongside the files.
+    let mut manifest = Manifest::load(workdir.resolved())?;
+    let mut tombstones = Tombstones::load(workdir.resolved())?;
+    let mut hashes = Hashes::load(workdir.resolved())?;
+    let mut tracked = Tracked::load(workdir.resolved())?;
+    manifest.retain(|p| package_lookup.contains_key(p));
+    tombstones.retain(|p| package_lookup.contains_key(p));
+    let large_files = pc.config.large_files.clone().unwrap_or_default();
+    let store = get_store(pc)?;
+    let mut offenders = vec![];
+    let mut deleted = vec![];
+    for (key, managed) in &package_lookup {
+        let mut copy = workdir.resolved().to_path_buf();
+        copy.push(key);
+        if let Some(resolved) = &managed.resolved {
+            // Privileged paths are staged on a best effort basis.
+            if managed.privileged && unreadable(resolved.resolved()) {
+                warn!(
+                    "<bold>Skipped:</> Cannot read <cyan>{}</>. Stage as a user who can to \
+                    sync it.",
+                    resolved.unresolved().display()
+                );
+                continue;
+            }
+            // The file was deleted on another machine, which is only undone
+            // if it was edited here since it was last synced. Otherwise the
+            // deletion has simply not been applied here yet.
+            if tombstones.contains(key) {
+                match tracked.base(key) {
+                    Some(base) if hashes.hash(resolved.resolved())? != Some(base) => info!(
+                        "<bold>Restored:</> <cyan>{}</> was deleted on another machine but \
+                        has changed here since.",
+                        key.display()
+                    ),
+                    _ => continue,
+                }
+            }
+            let stored = match inspect(&large_files, resolved.resolved(), managed.symlinks)? {
+                None => None,
+                Some(offense) if !allowed(&large_files, key, managed) => {
+                    offenders.push((key, offense));
+                    continue;
+                }
+                Some(Offense::TooLarge(..)) => store.as_ref(),
+                Some(Offense::Binary) => None,
+            };
             if let Some(p) = copy.parent() {
                 fs::create_dir_all(p)?;
             }
-            fs::copy(value.resolved(), copy)?;
+            let copied = match stored {
+                Some(store) => stage_pointer(resolved.resolved(), &copy, store, &mut hashes)?,
+                None => stage_file(resolved.resolved(), &copy, managed.symlinks, &mut hashes)?,
+            };
+            changed |= copied == Copied::Written;
+            // There is no metadata of links to record.
+            if copied != Copied::Skipped && !copy.is_symlink() {
+                manifest.insert(key, Metadata::of(resolved.resolved())?);
+                metadata::warn_exposed(resolved.resolved());
+                set_base(&repo, &mut hashes, &mut tracked, key, &copy)?;
+            } else {
+                tracked.insert(key);
+            }
+            tombstones.remove(key);
+        } else if tracked.contains(key)
+            && managed
+                .candidates
+                .iter()
+                .any(|c| path::normalize(c).is_ok())
+        {
+            // The file existed when we last looked, so it has since been
+            // deleted (as opposed to its path no longer expanding).
+            if fs::symlink_metadata(&copy).is_err() {
+                tracked.remove(key);
+                continue;
+            }
+            match confirm_deletion(
+                key,
+                pc.config.on_delete,
+                "Remove it from the local repository?",
+            )? {
+                Some(true) => {
+                    deleted.push(key.clone());
+                    tombstones.insert(key);
+                    tracked.remove(key);
+                }
+                Some(false) => tracked.remove(key),
+                None => warn!(
+                    "<cyan>{}</> was deleted. Run `<italic>homesync stage</>` interactively to \
+                    decide whether to remove it from the local repository.",
+                    key.display()
+                ),
+            }
+        }
+    }
+    if !offenders.is_empty() {
+        for (key, offense) in &offenders {
+            warn!("<bold>Skipped:</> <cyan>{}</> {}.", key.display(), offense);
         }
+        info!(
+            "List them under `<italic>large_files.allow</>` to stage them anyway, or \
+            remove them from their packages."
+        );
     }
+    manifest.retain(|p| !deleted.iter().any(|d| d == p));
+    changed |= unstage(pc, &deleted)?;
+    changed |= manifest.save()?;
+    changed |= tombstones.save()?;
+    hashes.save()?;
+    tracked.save()?;
 
     info!(
         "<bold>Staged:</> View using `<italic>git -C <cyan>{}</> <italic>status</>`.",
         &pc.config.repos.local.display()
     );
 
-    Ok(())
+    Ok(changed)
+}
+
+/// Removes the specified (unresolved) paths from the local repository, e.g.
+/// after they have been removed from a package.
+///
+/// Returns whether any of them were found.
+pub fn unstage(pc: &PathConfig, paths: &[PathBuf]) -> Result<bool> {
+    let workdir = get_workdir(pc)?;
+    let mut removed = false;
+    for path in paths {
+        let mut repo_file = workdir.resolved().to_path_buf();
+        repo_file.push(repo_key(path));
+        match fs::remove_file(&repo_file) {
+            Ok(()) => info!(
+                "<bold>Removed:</> <cyan>{}</> from local repository.",
+                path.display()
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => Err(e)?,
+        }
+        removed = true;
+        // Clean up any directories left empty, stopping at the repository
+        // root.
+        let mut parent = repo_file.parent();
+        while let Some(p) = parent {
+            if p == workdir.resolved() || p.read_dir()?.next().is_some() {
+                break;
+            }
+            fs::remove_dir(p)?;
+            parent = p.parent();
+        }
+    }
+    Ok(removed)
 }
 
 // ========================================
@@ -214,13 +520,28 @@ fn get_workdir(pc: &PathConfig) -> Result<ResPathBuf> {
     }
 }
 
+// The store of large files, if the config sets one up.
+fn get_store(pc: &PathConfig) -> Result<Option<Store>> {
+    match pc
+        .config
+        .large_files
+        .as_ref()
+        .and_then(|l| l.store.as_ref())
+    {
+        Some(dir) => Ok(Some(Store::new(&path::expand(dir)?))),
+        None => Ok(None),
+    }
+}
+
 fn recursive_walk_repo(root: &Path, path: &Path) -> Result<Vec<ResPathBuf>> {
     let mut seen = Vec::new();
     if path.is_dir() {
         for entry in fs::read_dir(path)? {
-            let nested = entry?.path();
-            if nested.is_dir() {
-                if nested.ends_with(".git") {
+            let entry = entry?;
+            let nested = entry.path();
+            // Symlinks are synced as is, even if pointing to a directory.
+            if entry.file_type()?.is_dir() {
+                if nested.ends_with(".git") || nested == root.join(".homesync") {
                     continue;
                 }
                 let nested = recursive_walk_repo(root, &nested)?;
@@ -240,20 +561,636 @@ fn walk_repo(root: &Path) -> Result<Vec<ResPathBuf>> {
     recursive_walk_repo(root, root)
 }
 
-fn get_package_lookup(pc: &PathConfig) -> HashMap<PathBuf, Option<ResPathBuf>> {
+// A path listed in the config, along with how to treat it if a symlink.
+struct Managed {
+    // The package listing the path.
+    package: String,
+    // Where the file may live on this machine, in order of preference. Just
+    // the path itself unless part of a `one_of` group or mapped elsewhere by
+    // the `hosts` entry of this machine.
+    candidates: Vec<PathBuf>,
+    // The location of the first candidate that currently exists on this
+    // machine. Kept lexical so that symlinks are not replaced by their
+    // targets.
+    resolved: Option<ResPathBuf>,
+    symlinks: Symlinks,
+    // Whether the path may require root to write to, along with the command
+    // to escalate writes with (if any).
+    privileged: bool,
+    escalate: Option<Vec<String>>,
+}
+
+impl Managed {
+    // Where to apply the file to, i.e. the first candidate that exists or else
+    // the first that expands.
+    fn target(&self) -> Option<PathBuf> {
+        match &self.resolved {
+            Some(resolved) => Some(resolved.resolved().to_path_buf()),
+            None => self.candidates.iter().find_map(|c| path::expand(c).ok()),
+        }
+    }
+}
+
+// The path a managed file is held under in the local repository. Absolute
+// paths (e.g. within `/etc`) are held relative to the repository root.
+fn repo_key(path: &Path) -> PathBuf {
+    path.strip_prefix("/").unwrap_or(path).to_path_buf()
+}
+
+// Whether a managed file is listed under `large_files.allow`, by way of any
+// path it may be found at.
+fn allowed(large_files: &LargeFiles, key: &Path, managed: &Managed) -> bool {
+    large_files
+        .allow
+        .iter()
+        .map(|a| repo_key(a))
+        .any(|a| a == key || managed.candidates.iter().any(|c| repo_key(c) == a))
+}
+
+// Whether the file at the given path exists but we lack permission to read it.
+fn unreadable(path: &Path) -> bool {
+    matches!(fs::File::open(path), Err(e) if e.kind() == io::ErrorKind::PermissionDenied)
+}
+
+// Maps each path the local repository may hold to how it is managed. Groups of
+// alternates are only held under their first path.
+fn get_package_lookup(pc: &PathConfig) -> HashMap<PathBuf, Managed> {
+    let host = pc.host();
     let mut seen = HashMap::new();
-    for (_, packages) in &pc.config.packages {
-        for path in packages {
-            if let Ok(resolved) = path::resolve(path) {
-                seen.insert(path.to_path_buf(), Some(resolved));
-            } else {
-                seen.insert(path.to_path_buf(), None);
-            }
+    for (name, package) in &pc.config.packages {
+        let escalate = pc.escalate(name);
+        for candidates in package.groups() {
+            let key = match candidates.first() {
+                Some(key) => key,
+                None => continue,
+            };
+            let candidates = match host.and_then(|h| h.paths.get(key)) {
+                Some(mapped) => std::slice::from_ref(mapped),
+                None => candidates,
+            };
+            let resolved = candidates.iter().find_map(|c| {
+                path::normalize(c)
+                    .ok()
+                    .filter(|n| fs::symlink_metadata(n.resolved()).is_ok())
+            });
+            seen.insert(
+                repo_key(key),
+                Managed {
+                    package: name.clone(),
+                    candidates: candidates.to_vec(),
+                    resolved,
+                    symlinks: pc.symlinks(name, key),
+                    privileged: package.privileged,
+                    escalate: escalate.clone(),
+                },
+            );
         }
     }
     seen
 }
 
+// A hidden file next to `path` to stage its replacement in. It must live in
+// the same directory for the final rename to be atomic.
+fn temp_sibling(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".homesync.tmp");
+    path.with_file_name(name)
+}
+
+/// Atomically replaces the file at `target` with whatever `write` writes. The
+/// new contents go to a temporary file in the same directory, which is synced
+/// to disk and then renamed over `target`, so that nobody ever observes a
+/// partially written file.
+///
+/// The replacement is given the specified permissions, if any. Otherwise it
+/// keeps those of the file it replaces. Ownership of the replaced file is kept
+/// where permitted.
+pub fn write_atomic<F>(
+    target: &Path,
+    permissions: Option<fs::Permissions>,
+    write: F,
+) -> io::Result<()>
+where
+    F: FnOnce(&mut fs::File) -> io::Result<()>,
+{
+    let existing = fs::metadata(target).ok();
+    let temp = temp_sibling(target);
+    let result = (|| {
+        // The temporary file's name is predictable, so never write through
+        // whatever (e.g. a symlink) was left there.
+        match fs::remove_file(&temp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e)?,
+            _ => (),
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&temp)?;
+        // Restrict access before writing anything private.
+        let permissions = permissions.or_else(|| existing.as_ref().map(|m| m.permissions()));
+        if let Some(permissions) = permissions {
+            file.set_permissions(permissions)?;
+        }
+        write(&mut file)?;
+        file.sync_all()?;
+        if let Some(existing) = &existing {
+            // Only privileged users may give away files.
+            let _ = unix::fs::fchown(&file, Some(existing.uid()), Some(existing.gid()));
+        }
+        fs::rename(&temp, target)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+    // Persist the rename itself.
+    if let Some(parent) = target.parent() {
+        if let Ok(dir) = fs::File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+// Atomically replaces `dst` with a copy of `src`. Refer to
+// [write_atomic](fn.write_atomic.html).
+fn copy_atomic(src: &Path, dst: &Path, permissions: Option<fs::Permissions>) -> Result<()> {
+    let mut source = fs::File::open(src)?;
+    write_atomic(dst, permissions, |file| {
+        io::copy(&mut source, file).map(|_| ())
+    })?;
+    Ok(())
+}
+
+// Atomically replaces `dst` with whatever `write` writes, like
+// [write_atomic](fn.write_atomic.html), falling back to the given escalation
+// command if we lack permission to do so ourselves. The escalated write stages
+// the new contents in a freshly created directory only we can access, so that
+// nobody else can read or swap them, and installs them over `dst` by way of
+// `install(1)`, keeping the owner of any file it replaces.
+fn write_escalated<F>(
+    dst: &Path,
+    permissions: Option<fs::Permissions>,
+    escalate: Option<&[String]>,
+    write: F,
+) -> Result<()>
+where
+    F: Fn(&mut fs::File) -> io::Result<()>,
+{
+    let escalate = match (write_atomic(dst, permissions.clone(), &write), escalate) {
+        (Ok(()), _) => return Ok(()),
+        (Err(e), Some(escalate))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound
+            ) =>
+        {
+            escalate
+        }
+        (Err(e), _) => Err(e)?,
+    };
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let dir = env::temp_dir().join(format!("homesync-{}-{}", process::id(), nonce));
+    // Fails if anything (e.g. a planted symlink) already stands in the way.
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let temp = dir.join("contents");
+    let result = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp)?;
+        write(&mut file)?;
+        file.sync_all()?;
+        let existing = fs::metadata(dst).ok();
+        let mode = permissions
+            .or_else(|| existing.as_ref().map(|m| m.permissions()))
+            .map_or(0o644, |p| p.mode() & 0o7777);
+        let mut command = process::Command::new(&escalate[0]);
+        command
+            .args(&escalate[1..])
+            .args(["install", "-D", "-m", &format!("{:o}", mode)]);
+        if let Some(existing) = &existing {
+            command
+                .args(["-o", &existing.uid().to_string()])
+                .args(["-g", &existing.gid().to_string()]);
+        }
+        let status = command.arg(&temp).arg(dst).status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "`{}` failed with {}.",
+                escalate.join(" "),
+                status
+            )))
+        }
+    })();
+    let _ = fs::remove_dir_all(&dir);
+    result?;
+    Ok(())
+}
+
+// Atomically replaces `dst` with a symlink pointing to the same place as the
+// symlink `src`.
+fn link_atomic(src: &Path, dst: &Path) -> Result<()> {
+    let temp = temp_sibling(dst);
+    let _ = fs::remove_file(&temp);
+    unix::fs::symlink(fs::read_link(src)?, &temp)?;
+    if let Err(e) = fs::rename(&temp, dst) {
+        let _ = fs::remove_file(&temp);
+        Err(e)?;
+    }
+    Ok(())
+}
+
+// Whether `dst` is a symlink pointing to the same place as the symlink `src`.
+fn same_link(src: &Path, dst: &Path) -> Result<bool> {
+    Ok(fs::read_link(dst).ok() == Some(fs::read_link(src)?))
+}
+
+fn reject(path: &Path) {
+    warn!(
+        "<bold>Skipped:</> <cyan>{}</> is a symlink and symlinks are rejected.",
+        path.display()
+    );
+}
+
+// The outcome of copying a single file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Copied {
+    // The file (or link) was written out.
+    Written,
+    // The destination already held the same contents, so was left untouched.
+    Unchanged,
+    // Changes from both sides were merged into the destination, which holds
+    // contents of its own rather than those recorded.
+    Merged,
+    // Nothing was copied, e.g. due to the symlinks policy.
+    Skipped,
+}
+
+// Copies a managed file into the local repository. Links count as written
+// only if they had to be replaced, and otherwise as skipped.
+fn stage_file(src: &Path, dst: &Path, symlinks: Symlinks, hashes: &mut Hashes) -> Result<Copied> {
+    if src.is_symlink() {
+        match symlinks {
+            Symlinks::Reject => {
+                reject(src);
+                return Ok(Copied::Skipped);
+            }
+            Symlinks::Link => {
+                if same_link(src, dst)? {
+                    return Ok(Copied::Skipped);
+                }
+                link_atomic(src, dst)?;
+                return Ok(Copied::Written);
+            }
+            // Nothing to copy if the link is dangling.
+            Symlinks::Follow if !src.exists() => return Ok(Copied::Skipped),
+            Symlinks::Follow => (),
+        }
+    }
+    // The repository copy always mirrors the mode of the original. Renaming
+    // over `dst` also replaces any link staged under a different policy.
+    let permissions = fs::metadata(src)?.permissions();
+    if !dst.is_symlink() && hashes.same(src, dst)? {
+        if fs::metadata(dst)?.permissions() != permissions {
+            fs::set_permissions(dst, permissions)?;
+        }
+        return Ok(Copied::Unchanged);
+    }
+    copy_atomic(src, dst, Some(permissions))?;
+    Ok(Copied::Written)
+}
+
+// Why a file is kept out of the local repository.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Offense {
+    // The size of the file and the limit it exceeds.
+    TooLarge(u64, u64),
+    Binary,
+}
+
+impl fmt::Display for Offense {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Offense::TooLarge(size, limit) => write!(
+                f,
+                "is {}, over the limit of {}",
+                format_size(*size),
+                format_size(*limit)
+            ),
+            Offense::Binary => write!(f, "looks like a binary file"),
+        }
+    }
+}
+
+// Formats a number of bytes for humans, e.g. `1.5 MiB`.
+fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in ["KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    match unit {
+        "B" => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, unit),
+    }
+}
+
+// What, if anything, speaks against staging the given file. Links synced as
+// is are never held against.
+fn inspect(large_files: &LargeFiles, src: &Path, symlinks: Symlinks) -> Result<Option<Offense>> {
+    if src.is_symlink() && symlinks != Symlinks::Follow {
+        return Ok(None);
+    }
+    let size = match fs::metadata(src) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return Ok(None),
+    };
+    if size > large_files.max_size() {
+        Ok(Some(Offense::TooLarge(size, large_files.max_size())))
+    } else if !large_files.allow_binary && store::is_binary(src)? {
+        Ok(Some(Offense::Binary))
+    } else {
+        Ok(None)
+    }
+}
+
+// Copies a managed file into the store, leaving a pointer to it in the local
+// repository in its place.
+fn stage_pointer(src: &Path, dst: &Path, store: &Store, hashes: &mut Hashes) -> Result<Copied> {
+    let oid = match hashes.hash(src)? {
+        Some(oid) => oid,
+        None => return Ok(Copied::Skipped),
+    };
+    let permissions = fs::metadata(src)?.permissions();
+    let object = store.object(oid);
+    if !object.is_file() {
+        if let Some(p) = object.parent() {
+            fs::create_dir_all(p)?;
+        }
+        copy_atomic(src, &object, Some(permissions.clone()))?;
+    }
+    let pointer = Pointer {
+        oid,
+        size: fs::metadata(&object)?.len(),
+    };
+    if !dst.is_symlink() && Pointer::read(dst)? == Some(pointer) {
+        if fs::metadata(dst)?.permissions() != permissions {
+            fs::set_permissions(dst, permissions)?;
+        }
+        return Ok(Copied::Unchanged);
+    }
+    let contents = pointer.to_string();
+    write_atomic(dst, Some(permissions), |file| {
+        file.write_all(contents.as_bytes())
+    })?;
+    Ok(Copied::Written)
+}
+
+// Whether to propagate the deletion of the given (unresolved) path, as
+// dictated by the `on_delete` policy. `None` if we should have asked but could
+// not, in which case the decision is left to a later interactive run.
+fn confirm_deletion(
+    path: &Path,
+    on_delete: Option<OnDelete>,
+    question: &str,
+) -> Result<Option<bool>> {
+    Ok(match on_delete.unwrap_or_default() {
+        OnDelete::Keep => Some(false),
+        OnDelete::Remove => Some(true),
+        OnDelete::Ask if !prompt::is_interactive() => None,
+        OnDelete::Ask => Some(prompt::confirm(
+            &format!("{} was deleted. {}", path.display(), question),
+            false,
+        )?),
+    })
+}
+
+// A name not yet taken to back up the given file under, i.e. `<file>.bak` or
+// else `<file>.bak.<n>`.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_os_string();
+    backup.push(".bak");
+    let mut candidate = PathBuf::from(&backup);
+    let mut n = 0;
+    while fs::symlink_metadata(&candidate).is_ok() {
+        n += 1;
+        let mut numbered = backup.clone();
+        numbered.push(format!(".{}", n));
+        candidate = PathBuf::from(numbered);
+    }
+    candidate
+}
+
+// Removes a managed file deleted on another machine, keeping a backup of it
+// alongside (refer to `backup_path`). Returns whether it was removed.
+fn apply_deletion(path: &Path, target: &Path, on_delete: Option<OnDelete>) -> Result<bool> {
+    match confirm_deletion(path, on_delete, "Remove it from this machine?")? {
+        Some(true) => {
+            let backup = backup_path(target);
+            fs::rename(target, &backup)?;
+            info!(
+                "<bold>Removed:</> <cyan>{}</> (backed up to <cyan>{}</>).",
+                path.display(),
+                backup.display()
+            );
+            Ok(true)
+        }
+        Some(false) => Ok(false),
+        None => {
+            warn!(
+                "<cyan>{}</> was deleted on another machine. Run `<italic>homesync apply</>` \
+                interactively to decide whether to remove it here.",
+                path.display()
+            );
+            Ok(false)
+        }
+    }
+}
+
+// Tracks the given (unresolved) path, taking the contents of `file` in the
+// local repository (or those it points to) as its new base. The base is
+// written into the object database so that it is still around to merge
+// against later.
+fn set_base(
+    repo: &Repository,
+    hashes: &mut Hashes,
+    tracked: &mut Tracked,
+    path: &Path,
+    file: &Path,
+) -> Result<()> {
+    // The contents of stored files are not kept in git at all.
+    if let Some(pointer) = Pointer::read(file)? {
+        tracked.set_base(path, pointer.oid);
+        return Ok(());
+    }
+    match hashes.hash(file)? {
+        Some(base) if !file.is_symlink() => {
+            if !repo.odb()?.exists(base) {
+                repo.blob_path(file)?;
+            }
+            tracked.set_base(path, base);
+        }
+        _ => tracked.insert(path),
+    }
+    Ok(())
+}
+
+// Decides what to do with a file changed on this machine since it was last
+// synced, returning `None` if it should be overwritten after all. Files
+// changed on both sides are only overwritten or merged if asked to.
+fn reconcile(
+    src: &Path,
+    dst: &Path,
+    repo: &Repository,
+    base: Oid,
+    theirs: Oid,
+    escalate: Option<&[String]>,
+) -> Result<Option<Copied>> {
+    if theirs == base {
+        info!(
+            "<bold>Kept:</> <cyan>{}</> has local changes. Stage them to sync them.",
+            dst.display()
+        );
+        return Ok(Some(Copied::Skipped));
+    }
+    warn!(
+        "<bold>Conflict:</> <cyan>{}</> changed both here and in the local repository.",
+        dst.display()
+    );
+    let answer = prompt::ask("Keep local changes, overwrite them or merge? [k/o/m]", "k")?;
+    match answer.to_lowercase().as_str() {
+        "o" | "overwrite" => Ok(None),
+        "m" | "merge" => {
+            if repo.find_blob(base).is_err() {
+                warn!(
+                    "Base of <cyan>{}</> is no longer available to merge against.",
+                    dst.display()
+                );
+                return Ok(Some(Copied::Skipped));
+            }
+            let ours = repo.blob_path(dst)?;
+            let theirs = repo.blob_path(src)?;
+            let merged = match git::merge_blobs(repo, base, ours, theirs)? {
+                Some(merged) => repo.find_blob(merged)?,
+                None => {
+                    warn!(
+                        "Could not merge <cyan>{}</> cleanly. Kept local changes.",
+                        dst.display()
+                    );
+                    return Ok(Some(Copied::Skipped));
+                }
+            };
+            write_escalated(dst, None, escalate, |file| file.write_all(merged.content()))?;
+            info!("<bold>Merged:</> <cyan>{}</>.", dst.display());
+            Ok(Some(Copied::Merged))
+        }
+        _ => Ok(Some(Copied::Skipped)),
+    }
+}
+
+// Restores the recorded metadata of a file just applied from the local
+// repository. Files left untouched or merged only have their mode restored
+// so that their modification time does not change or misrepresent them.
+fn restore_metadata(
+    manifest: &Manifest,
+    repo_file: &Path,
+    path: &Path,
+    dst: &Path,
+    written: bool,
+) -> Result<()> {
+    if repo_file.is_symlink() {
+        return Ok(());
+    }
+    match manifest.get(path) {
+        Some(metadata) if written => metadata.restore(dst)?,
+        Some(metadata) => metadata.restore_mode(dst)?,
+        None => (),
+    }
+    metadata::warn_exposed(dst);
+    Ok(())
+}
+
+// Copies a file out of the local repository. Local changes made since `base`
+// was last synced are not overwritten without asking. Writes we lack
+// permission for go through `escalate`, if given.
+fn apply_file(
+    src: &Path,
+    dst: &Path,
+    symlinks: Symlinks,
+    hashes: &mut Hashes,
+    repo: &Repository,
+    base: Option<Oid>,
+    escalate: Option<&[String]>,
+) -> Result<Copied> {
+    if src.is_symlink() {
+        if symlinks == Symlinks::Reject {
+            reject(src);
+            return Ok(Copied::Skipped);
+        }
+        if same_link(src, dst)? {
+            return Ok(Copied::Unchanged);
+        }
+        link_atomic(src, dst)?;
+        return Ok(Copied::Written);
+    }
+    let mut target = dst.to_path_buf();
+    if dst.is_symlink() {
+        match symlinks {
+            Symlinks::Reject => {
+                reject(dst);
+                return Ok(Copied::Skipped);
+            }
+            // The file is no longer a link on the machine that staged it, so
+            // replace the link itself.
+            Symlinks::Link => (),
+            // Write through to the link's target, creating it if dangling. A
+            // missing target has no local edits to reconcile, so is written
+            // like any other new file.
+            Symlinks::Follow => match fs::canonicalize(dst) {
+                Ok(resolved) => target = resolved,
+                Err(_) => {
+                    let link = fs::read_link(dst)?;
+                    target = dst.parent().unwrap_or(Path::new("/")).join(link);
+                }
+            },
+        }
+    }
+    if !target.is_symlink() && hashes.same(src, &target)? {
+        return Ok(Copied::Unchanged);
+    }
+    if let (Some(base), false) = (base, target.is_symlink()) {
+        if let (Some(ours), Some(theirs)) = (hashes.hash(&target)?, hashes.hash(src)?) {
+            if ours != base {
+                if let Some(copied) = reconcile(src, &target, repo, base, theirs, escalate)? {
+                    return Ok(copied);
+                }
+            }
+        }
+    }
+    // Existing files keep their mode. New ones start out with the mode they
+    // were staged with.
+    let permissions = match fs::metadata(&target) {
+        Ok(_) => None,
+        Err(_) => Some(fs::metadata(src)?.permissions()),
+    };
+    write_escalated(&target, permissions, escalate, |file| {
+        io::copy(&mut fs::File::open(src)?, file).map(|_| ())
+    })?;
+    Ok(Copied::Written)
+}
+
 // ========================================
 // Tests
 // ========================================
@@ -263,7 +1200,7 @@ mod tests {
     use super::*;
     use crate::{config, path};
     use git2::Repository;
-    use std::{env, fs::File, io::Write};
+    use std::{fs::File, io::Write};
     use tempfile::TempDir;
 
     // Tests must be serial since we are updating our environment variables.
@@ -271,7 +1208,7 @@ mod tests {
 
     // Wrap functionality around this method to ensure the temporary directory
     // does not go out of scope before we are ready.
-    fn build_home<T: Fn(&config::PathConfig, &Path)>(func: T) {
+    fn build_home<T: Fn(&mut config::PathConfig, &Path)>(func: T) {
         let temp_dir = TempDir::new().unwrap();
 
         let mut home_dir = temp_dir.path().to_path_buf();
@@ -289,13 +1226,14 @@ mod tests {
         config_homesync_yml.push("homesync.yml");
         File::create(&config_homesync_yml).unwrap();
 
-        env::set_var("HOME", &home_dir);
-        env::set_var("XDG_CONFIG_HOME", "");
+        let mut vars = path::EnvGuard::new();
+        vars.set("HOME", &home_dir);
+        vars.set("XDG_CONFIG_HOME", "");
 
         let template = path::resolve(Path::new("rsrc/template.yml")).unwrap();
-        let config = config::load(&vec![template]).unwrap();
+        let mut config = config::load(&vec![template]).unwrap();
 
-        func(&config, &home_dir);
+        func(&mut config, &home_dir);
     }
 
     fn build_repo(pc: &PathConfig) -> PathBuf {
@@ -333,25 +1271,25 @@ mod tests {
     #[test]
     #[serial]
     fn package_lookup() {
-        build_home(|pc, _home_dir| {
-            let lookup = super::get_package_lookup(pc);
-            assert_eq!(lookup.len(), 4);
-            assert_eq!(
-                lookup
-                    .iter()
-                    .filter(|(_, v)| v.is_some())
-                    .collect::<HashMap<_, _>>()
-                    .len(),
-                2
-            );
+        build_home(|pc, home_dir| {
+            let mut lookup = super::get_package_lookup(pc);
+            pc.config.packages.get_mut("homesync").unwrap().paths = vec![
+                PathBuf::from("$HOME/.config/homesync/homesync.yml"),
+                PathBuf::from("$HOME/.missing.yml"),
+            ];
+            lookup.extend(super::get_package_lookup(pc));
+            assert_eq!(lookup.len(), 3);
+            // Alternates are looked up under the first path of their group,
+            // resolving to the first that exists.
+            let managed = &lookup[Path::new("$HOME/.homesync.yml")];
+            assert_eq!(managed.candidates.len(), 4);
             assert_eq!(
-                lookup
-                    .iter()
-                    .filter(|(_, v)| v.is_none())
-                    .collect::<HashMap<_, _>>()
-                    .len(),
-                2
+                managed.resolved.as_ref().unwrap().resolved(),
+                &home_dir.join(".homesync.yml")
             );
+            let path = Path::new("$HOME/.config/homesync/homesync.yml");
+            assert!(lookup[path].resolved.is_some());
+            assert!(lookup[Path::new("$HOME/.missing.yml")].resolved.is_none());
         });
     }
 
@@ -360,24 +1298,19 @@ mod tests {
     fn apply_all() {
         build_home(|pc, home_dir| {
             let repo_dir = build_repo(pc);
-            let targets = [".homesync.yml", ".config/homesync/homesync.yml"];
-
-            for target in &targets {
-                let mut repo_path = repo_dir.to_path_buf();
-                repo_path.push(&format!("$HOME/{}", target));
-                fs::create_dir_all(repo_path.parent().unwrap()).unwrap();
-                let mut file = File::create(&repo_path).unwrap();
-                file.write_all(b"Hello, world!").unwrap();
-            }
+            let repo_path = repo_dir.join("$HOME/.homesync.yml");
+            fs::create_dir_all(repo_path.parent().unwrap()).unwrap();
+            let mut file = File::create(&repo_path).unwrap();
+            file.write_all(b"Hello, world!").unwrap();
 
             super::apply_all(pc).expect("Could not apply packages");
 
-            for target in &targets {
-                let mut home_path = home_dir.to_path_buf();
-                home_path.push(target);
-                let contents = fs::read_to_string(&home_path).unwrap();
-                assert_eq!(contents, "Hello, world!");
-            }
+            // Only the first alternate that exists is written to.
+            let home_path = home_dir.join(".homesync.yml");
+            let contents = fs::read_to_string(&home_path).unwrap();
+            assert_eq!(contents, "Hello, world!");
+            let home_path = home_dir.join(".config/homesync/homesync.yml");
+            assert_eq!(fs::read_to_string(&home_path).unwrap(), "");
         });
     }
 
@@ -386,24 +1319,50 @@ mod tests {
     fn apply_one() {
         build_home(|pc, home_dir| {
             let repo_dir = build_repo(pc);
-            let targets = [".homesync.yml", ".config/homesync/homesync.yml"];
-
-            for target in &targets {
-                let mut repo_path = repo_dir.to_path_buf();
-                repo_path.push(&format!("$HOME/{}", target));
-                fs::create_dir_all(repo_path.parent().unwrap()).unwrap();
-                let mut file = File::create(&repo_path).unwrap();
-                file.write_all(b"Hello, world!").unwrap();
-            }
+            let repo_path = repo_dir.join("$HOME/.homesync.yml");
+            fs::create_dir_all(repo_path.parent().unwrap()).unwrap();
+            let mut file = File::create(&repo_path).unwrap();
+            file.write_all(b"Hello, world!").unwrap();
 
+            // Without any existing alternate, the first that expands is used.
+            fs::remove_file(home_dir.join(".homesync.yml")).unwrap();
+            fs::remove_file(home_dir.join(".config/homesync/homesync.yml")).unwrap();
             super::apply_one(pc, "homesync").expect("Could not apply `homesync`");
 
-            for target in &targets {
-                let mut home_path = home_dir.to_path_buf();
-                home_path.push(target);
-                let contents = fs::read_to_string(&home_path).unwrap();
-                assert_eq!(contents, "Hello, world!");
-            }
+            let home_path = home_dir.join(".homesync.yml");
+            let contents = fs::read_to_string(&home_path).unwrap();
+            assert_eq!(contents, "Hello, world!");
+            assert!(!home_dir.join(".config/homesync/homesync.yml").exists());
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn hosts() {
+        build_home(|pc, home_dir| {
+            let repo_dir = build_repo(pc);
+            let key = PathBuf::from("$HOME/.homesync.yml");
+            let mut host = config::Host::default();
+            host.paths
+                .insert(key.clone(), PathBuf::from("$HOME/Library/homesync.yml"));
+            pc.config.hosts.insert("laptop".to_owned(), host);
+            fs::create_dir_all(repo_dir.join(&key).parent().unwrap()).unwrap();
+            fs::write(repo_dir.join(&key), "Hello, world!").unwrap();
+
+            // Other hosts are unaffected.
+            let mut vars = path::EnvGuard::new();
+            vars.set("HOMESYNC_HOST", "desktop");
+            let lookup = super::get_package_lookup(pc);
+            assert_eq!(lookup[&key].candidates.len(), 4);
+
+            // Mapped files are applied to wherever this host keeps them.
+            vars.set("HOMESYNC_HOST", "laptop");
+            super::apply_all(pc).unwrap();
+            drop(vars);
+            let contents = fs::read_to_string(home_dir.join("Library/homesync.yml")).unwrap();
+            assert_eq!(contents, "Hello, world!");
+            let home_path = home_dir.join(".homesync.yml");
+            assert_eq!(fs::read_to_string(home_path).unwrap(), "");
         });
     }
 
@@ -412,22 +1371,481 @@ mod tests {
     fn stage() {
         build_home(|pc, _home_dir| {
             let repo_dir = build_repo(pc);
-            super::stage(pc).expect("Could not stage files.");
+            fs::write(repo_dir.join(".gitignore"), "").unwrap();
+            let walked = || {
+                let mut walked: Vec<PathBuf> = super::walk_repo(&repo_dir)
+                    .unwrap()
+                    .iter()
+                    .map(|w| w.unresolved().to_path_buf())
+                    .collect();
+                walked.sort();
+                walked
+            };
+            // Files no longer referenced in the config are kept unless pruning.
+            assert!(super::stage(pc, Prune::Keep).expect("Could not stage files."));
+            assert_eq!(walked().len(), 4);
+            assert!(!super::stage(pc, Prune::Keep).expect("Could not stage files."));
             // Copied over the files in $HOME that exist, and deleted files that
             // were previously defined but not referenced in the config.
-            let walked = super::walk_repo(&repo_dir).unwrap();
-            let mut walked: Vec<PathBuf> = walked
-                .iter()
-                .map(|w| w.unresolved().to_path_buf())
-                .collect();
-            walked.sort();
+            // Protected files always stay.
+            assert!(super::stage(pc, Prune::Remove).expect("Could not stage files."));
             assert_eq!(
-                walked,
+                walked(),
                 vec![
-                    PathBuf::from("$HOME/.config/homesync/homesync.yml"),
                     PathBuf::from("$HOME/.homesync.yml"),
+                    PathBuf::from(".gitignore"),
                 ]
             );
+            assert!(!repo_dir.join("b").exists());
+            // Modes are recorded outside of the tracked files.
+            let manifest = Manifest::load(&repo_dir).unwrap();
+            let path = Path::new("$HOME/.homesync.yml");
+            assert!(manifest.get(path).is_some());
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn read_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        build_home(|pc, home_dir| {
+            let repo_dir = build_repo(pc);
+            let path = Path::new("$HOME/.homesync.yml");
+            let home_path = home_dir.join(".homesync.yml");
+            fs::write(&home_path, "key").unwrap();
+            fs::set_permissions(&home_path, fs::Permissions::from_mode(0o400)).unwrap();
+            super::stage(pc, Prune::Keep).unwrap();
+            let mtime = Manifest::load(&repo_dir).unwrap().get(path).unwrap().mtime;
+
+            // Applying over a read-only file keeps its mode and restores the
+            // recorded modification time.
+            fs::write(repo_dir.join(path), "new key").unwrap();
+            super::apply_one(pc, "homesync").unwrap();
+            assert_eq!(fs::read_to_string(&home_path).unwrap(), "new key");
+            let metadata = Metadata::of(&home_path).unwrap();
+            assert_eq!((metadata.mode, metadata.mtime), (0o400, mtime));
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn deletion() {
+        build_home(|pc, home_dir| {
+            let repo_dir = build_repo(pc);
+            pc.config.on_delete = Some(OnDelete::Remove);
+            let path = Path::new("$HOME/.homesync.yml");
+            super::stage(pc, Prune::Keep).unwrap();
+            assert!(repo_dir.join(path).exists());
+
+            // Deleting the file removes it from the repository, leaving a
+            // tombstone behind.
+            fs::remove_file(home_dir.join(".homesync.yml")).unwrap();
+            fs::remove_file(home_dir.join(".config/homesync/homesync.yml")).unwrap();
+            super::stage(pc, Prune::Keep).unwrap();
+            assert!(!repo_dir.join(path).exists());
+            assert!(Tombstones::load(&repo_dir).unwrap().contains(path));
+
+            // Other machines back up and remove their copy on apply.
+            fs::write(home_dir.join(".homesync.yml"), "other").unwrap();
+            super::apply_all(pc).unwrap();
+            assert!(!home_dir.join(".homesync.yml").exists());
+            let backup = fs::read_to_string(home_dir.join(".homesync.yml.bak")).unwrap();
+            assert_eq!(backup, "other");
+
+            // Staging on a machine the deletion was not applied to yet does
+            // not bring the file back, unless it was edited there since.
+            let mut tombstones = Tombstones::load(&repo_dir).unwrap();
+            tombstones.remove(path);
+            tombstones.save().unwrap();
+            fs::write(home_dir.join(".homesync.yml"), "synced").unwrap();
+            super::stage(pc, Prune::Keep).unwrap();
+            fs::remove_file(repo_dir.join(path)).unwrap();
+            tombstones.insert(path);
+            tombstones.save().unwrap();
+            super::stage(pc, Prune::Keep).unwrap();
+            assert!(!repo_dir.join(path).exists());
+            assert!(Tombstones::load(&repo_dir).unwrap().contains(path));
+
+            fs::write(home_dir.join(".homesync.yml"), "edited").unwrap();
+            super::stage(pc, Prune::Keep).unwrap();
+            assert_eq!(fs::read_to_string(repo_dir.join(path)).unwrap(), "edited");
+            assert!(!Tombstones::load(&repo_dir).unwrap().contains(path));
+
+            // Existing backups are never overwritten.
+            fs::remove_file(repo_dir.join(path)).unwrap();
+            tombstones.insert(path);
+            tombstones.save().unwrap();
+            super::apply_all(pc).unwrap();
+            let backup = fs::read_to_string(home_dir.join(".homesync.yml.bak.1")).unwrap();
+            assert_eq!(backup, "edited");
+            let backup = fs::read_to_string(home_dir.join(".homesync.yml.bak")).unwrap();
+            assert_eq!(backup, "other");
+        });
+    }
+
+    #[test]
+    fn symlinks() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let target = dir.join("target");
+        fs::write(&target, "target").unwrap();
+        let link = dir.join("link");
+        unix::fs::symlink(&target, &link).unwrap();
+        let repo = Repository::init(dir).unwrap();
+        let mut hashes = Hashes::load(dir).unwrap();
+
+        let staged = dir.join("staged");
+        super::stage_file(&link, &staged, Symlinks::Link, &mut hashes).unwrap();
+        assert_eq!(fs::read_link(&staged).unwrap(), target);
+        super::stage_file(&link, &staged, Symlinks::Follow, &mut hashes).unwrap();
+        assert!(!staged.is_symlink());
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "target");
+        fs::remove_file(&staged).unwrap();
+        super::stage_file(&link, &staged, Symlinks::Reject, &mut hashes).unwrap();
+        assert!(fs::symlink_metadata(&staged).is_err());
+
+        // Links in the repository are restored as links.
+        let applied = dir.join("applied");
+        fs::write(&applied, "applied").unwrap();
+        super::stage_file(&link, &staged, Symlinks::Link, &mut hashes).unwrap();
+        assert_eq!(
+            super::apply_file(
+                &staged,
+                &applied,
+                Symlinks::Follow,
+                &mut hashes,
+                &repo,
+                None,
+                None
+            )
+            .unwrap(),
+            Copied::Written
+        );
+        assert_eq!(fs::read_link(&applied).unwrap(), target);
+
+        // Files in the repository are written through followed links.
+        fs::remove_file(&staged).unwrap();
+        fs::write(&staged, "staged").unwrap();
+        assert_eq!(
+            super::apply_file(
+                &staged,
+                &applied,
+                Symlinks::Reject,
+                &mut hashes,
+                &repo,
+                None,
+                None
+            )
+            .unwrap(),
+            Copied::Skipped
+        );
+        assert_eq!(
+            super::apply_file(
+                &staged,
+                &applied,
+                Symlinks::Follow,
+                &mut hashes,
+                &repo,
+                None,
+                None
+            )
+            .unwrap(),
+            Copied::Written
+        );
+        assert!(applied.is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "staged");
+
+        // Dangling links have their target created, with the staged mode.
+        fs::remove_file(&target).unwrap();
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o640)).unwrap();
+        let base = repo.blob(b"base").unwrap();
+        for expected in [Copied::Written, Copied::Unchanged] {
+            assert_eq!(
+                super::apply_file(
+                    &staged,
+                    &applied,
+                    Symlinks::Follow,
+                    &mut hashes,
+                    &repo,
+                    Some(base),
+                    None
+                )
+                .unwrap(),
+                expected
+            );
+        }
+        assert!(applied.is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "staged");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        // The created target is then reconciled like any other file.
+        fs::write(&target, "local").unwrap();
+        assert_eq!(
+            super::apply_file(
+                &staged,
+                &applied,
+                Symlinks::Follow,
+                &mut hashes,
+                &repo,
+                Some(base),
+                None
+            )
+            .unwrap(),
+            Copied::Skipped
+        );
+        assert_eq!(fs::read_to_string(&target).unwrap(), "local");
+    }
+
+    #[test]
+    fn unchanged() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let src = dir.join("src");
+        let dst = dir.join("dst");
+        fs::write(&src, "same").unwrap();
+        fs::write(&dst, "same").unwrap();
+        let mtime = fs::metadata(&dst).unwrap().modified().unwrap();
+
+        let repo = Repository::init(dir).unwrap();
+        let mut hashes = Hashes::load(dir).unwrap();
+        assert_eq!(
+            super::stage_file(&src, &dst, Symlinks::Follow, &mut hashes).unwrap(),
+            Copied::Unchanged
+        );
+        assert_eq!(
+            super::apply_file(&src, &dst, Symlinks::Follow, &mut hashes, &repo, None, None)
+                .unwrap(),
+            Copied::Unchanged
+        );
+        assert_eq!(fs::metadata(&dst).unwrap().modified().unwrap(), mtime);
+
+        fs::write(&src, "different").unwrap();
+        assert_eq!(
+            super::apply_file(&src, &dst, Symlinks::Follow, &mut hashes, &repo, None, None)
+                .unwrap(),
+            Copied::Written
+        );
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "different");
+    }
+
+    #[test]
+    fn conflicts() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let src = dir.join("src");
+        let dst = dir.join("dst");
+        let repo = Repository::init(dir).unwrap();
+        let mut hashes = Hashes::load(dir).unwrap();
+        let base = repo.blob(b"a\nb\nc\n").unwrap();
+
+        // Local changes are kept if the repository has nothing new.
+        fs::write(&src, "a\nb\nc\n").unwrap();
+        fs::write(&dst, "a\nb\nlocal\n").unwrap();
+        assert_eq!(
+            super::apply_file(
+                &src,
+                &dst,
+                Symlinks::Follow,
+                &mut hashes,
+                &repo,
+                Some(base),
+                None
+            )
+            .unwrap(),
+            Copied::Skipped
+        );
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "a\nb\nlocal\n");
+
+        // Files untouched since the last sync are overwritten.
+        fs::write(&src, "remote\nb\nc\n").unwrap();
+        fs::write(&dst, "a\nb\nc\n").unwrap();
+        assert_eq!(
+            super::apply_file(
+                &src,
+                &dst,
+                Symlinks::Follow,
+                &mut hashes,
+                &repo,
+                Some(base),
+                None
+            )
+            .unwrap(),
+            Copied::Written
+        );
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "remote\nb\nc\n");
+
+        // Files changed on both sides keep their local changes by default.
+        fs::write(&dst, "a\nb\nlocal\n").unwrap();
+        assert_eq!(
+            super::apply_file(
+                &src,
+                &dst,
+                Symlinks::Follow,
+                &mut hashes,
+                &repo,
+                Some(base),
+                None
+            )
+            .unwrap(),
+            Copied::Skipped
+        );
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "a\nb\nlocal\n");
+
+        // Changes to different lines merge cleanly.
+        let ours = repo.blob(b"a\nb\nlocal\n").unwrap();
+        let theirs = repo.blob(b"remote\nb\nc\n").unwrap();
+        let merged = git::merge_blobs(&repo, base, ours, theirs)
+            .unwrap()
+            .unwrap();
+        let merged = repo.find_blob(merged).unwrap();
+        assert_eq!(merged.content(), b"remote\nb\nlocal\n");
+        let theirs = repo.blob(b"a\nb\nremote\n").unwrap();
+        assert!(git::merge_blobs(&repo, base, ours, theirs)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn write_atomic() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "old").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+
+        super::write_atomic(&file, None, |f| f.write_all(b"new")).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        let mode = fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A failed write leaves the original untouched.
+        let failed = super::write_atomic(&file, None, |f| {
+            f.write_all(b"partial")?;
+            Err(io::Error::other("interrupted"))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+        // Anything left at the temporary path is replaced, not written through.
+        let victim = temp_dir.path().join("victim");
+        fs::write(&victim, "victim").unwrap();
+        unix::fs::symlink(&victim, super::temp_sibling(&file)).unwrap();
+        super::write_atomic(&file, None, |f| f.write_all(b"newer")).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "newer");
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "victim");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn write_escalated() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("missing/file");
+        let write = |f: &mut fs::File| f.write_all(b"new");
+        assert!(super::write_escalated(&file, None, None, write).is_err());
+
+        // Stands in for e.g. `sudo`, running `install` as is.
+        let escalate = ["env".to_owned()];
+        let permissions = fs::Permissions::from_mode(0o640);
+        super::write_escalated(&file, Some(permissions), Some(&escalate), write).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        assert_eq!(fs::metadata(&file).unwrap().mode() & 0o777, 0o640);
+
+        let failing = ["false".to_owned()];
+        let other = temp_dir.path().join("other/file");
+        assert!(super::write_escalated(&other, None, Some(&failing), write).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn privileged() {
+        build_home(|pc, home_dir| {
+            let repo_dir = build_repo(pc);
+            // Stands in for a file within `/etc`.
+            let etc_hosts = home_dir.parent().unwrap().join("etc/hosts");
+            fs::create_dir_all(etc_hosts.parent().unwrap()).unwrap();
+            fs::write(&etc_hosts, "127.0.0.1 localhost").unwrap();
+            let mut package = config::Package::default();
+            package.paths.push(etc_hosts.clone());
+            package.privileged = true;
+            pc.config.packages.insert("etc".to_owned(), package);
+            assert_eq!(pc.escalate("etc"), Some(vec!["sudo".to_owned()]));
+            assert_eq!(pc.escalate("homesync"), None);
+
+            // Absolute paths are held relative to the repository root.
+            super::stage(pc, Prune::Keep).unwrap();
+            let repo_file = repo_dir.join(etc_hosts.strip_prefix("/").unwrap());
+            let contents = fs::read_to_string(&repo_file).unwrap();
+            assert_eq!(contents, "127.0.0.1 localhost");
+
+            fs::write(&repo_file, "::1 localhost").unwrap();
+            pc.config.escalate = Some(vec![]);
+            assert_eq!(pc.escalate("etc"), None);
+            super::apply_one(pc, "etc").unwrap();
+            assert_eq!(fs::read_to_string(&etc_hosts).unwrap(), "::1 localhost");
+
+            super::unstage(pc, std::slice::from_ref(&etc_hosts)).unwrap();
+            assert!(!repo_file.exists());
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn large_files() {
+        build_home(|pc, home_dir| {
+            let repo_dir = build_repo(pc);
+            let store_dir = home_dir.join("store");
+            fs::write(home_dir.join("large"), "more than sixteen bytes").unwrap();
+            fs::write(home_dir.join("binary"), b"\x00\x01").unwrap();
+            fs::write(home_dir.join("allowed"), "also more than sixteen bytes").unwrap();
+            fs::write(home_dir.join("alternate"), b"\x00\x02").unwrap();
+            let mut package = config::Package::default();
+            for name in ["large", "binary", "allowed"] {
+                package.paths.push(PathBuf::from(format!("$HOME/{}", name)));
+            }
+            package.one_of.push(vec![
+                PathBuf::from("$HOME/missing"),
+                PathBuf::from("$HOME/alternate"),
+            ]);
+            pc.config.packages.insert("cache".to_owned(), package);
+            pc.config.large_files = Some(config::LargeFiles {
+                max_size: Some(16),
+                allow: vec![
+                    PathBuf::from("$HOME/allowed"),
+                    PathBuf::from("$HOME/alternate"),
+                ],
+                store: Some(store_dir.clone()),
+                ..Default::default()
+            });
+
+            // Only allowed files are staged, into the store if too large.
+            super::stage(pc, Prune::Keep).unwrap();
+            assert!(!repo_dir.join("$HOME/large").exists());
+            assert!(!repo_dir.join("$HOME/binary").exists());
+            let pointer = Pointer::read(&repo_dir.join("$HOME/allowed"))
+                .unwrap()
+                .unwrap();
+            let object = Store::new(&store_dir).object(pointer.oid);
+            let contents = fs::read_to_string(object).unwrap();
+            assert_eq!(contents, "also more than sixteen bytes");
+            // Any path of a `one_of` group may be allowed.
+            assert!(repo_dir.join("$HOME/missing").exists());
+
+            // Applying follows the pointer.
+            fs::remove_file(home_dir.join("allowed")).unwrap();
+            super::apply_one(pc, "cache").unwrap();
+            let contents = fs::read_to_string(home_dir.join("allowed")).unwrap();
+            assert_eq!(contents, "also more than sixteen bytes");
         });
     }
+
+    #[test]
+    fn format_size() {
+        assert_eq!(super::format_size(512), "512 B");
+        assert_eq!(super::format_size(1536), "1.5 KiB");
+        assert_eq!(super::format_size(500 * 1024 * 1024), "500.0 MiB");
+    }
 }
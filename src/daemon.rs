@@ -5,19 +5,243 @@
 //! changes) and monitoring any files/file paths specified within. On changes,
 //! it will automatically stage the files to the local repository.
 
-use super::{config, config::PathConfig, copy, path, path::ResPathBuf};
+use super::{
+    config,
+    config::{Notify, NotifyEvent, PathConfig, Symlinks},
+    copy, git, path,
+};
 use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
-use simplelog::{error, paris, trace, warn};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    flag,
+};
+use simplelog::{error, info, paris, trace, warn};
 use std::{
     collections::HashSet,
-    error::Error,
-    path::PathBuf,
-    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+    error, fmt, fs, io,
+    io::{Read, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc,
+    },
     thread,
     time::Duration,
 };
 
-// TODO(jrpotter): Add pid file to only allow one daemon at a time.
+// ========================================
+// Error
+// ========================================
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyRunning(u32),
+    ChannelClosed(&'static str),
+    ConfigError(config::Error),
+    CopyError(copy::Error),
+    GitError(git::Error),
+    IOError(io::Error),
+    NotifyError(notify::Error),
+    ThreadPanicked(&'static str),
+}
+
+impl From<config::Error> for Error {
+    fn from(err: config::Error) -> Error {
+        Error::ConfigError(err)
+    }
+}
+
+impl From<copy::Error> for Error {
+    fn from(err: copy::Error) -> Error {
+        Error::CopyError(err)
+    }
+}
+
+impl From<git::Error> for Error {
+    fn from(err: git::Error) -> Error {
+        Error::GitError(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IOError(err)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(err: notify::Error) -> Error {
+        Error::NotifyError(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::AlreadyRunning(pid) => {
+                write!(
+                    f,
+                    "Another homesync daemon is already running (pid {}).",
+                    pid
+                )
+            }
+            Error::ChannelClosed(name) => write!(f, "The {} channel closed unexpectedly.", name),
+            Error::ConfigError(e) => write!(f, "{}", e),
+            Error::CopyError(e) => write!(f, "{}", e),
+            Error::GitError(e) => write!(f, "{}", e),
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::NotifyError(e) => write!(f, "{}", e),
+            Error::ThreadPanicked(name) => write!(f, "The {} thread panicked.", name),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// ========================================
+// Notifications
+// ========================================
+
+/// A destination for notifications, e.g. a desktop notification daemon.
+pub trait Sink {
+    fn send(&self, event: NotifyEvent, message: &str) -> io::Result<()>;
+}
+
+/// Runs the configured command with the message appended as its final
+/// argument. The event and message are also exposed through the
+/// `HOMESYNC_EVENT` and `HOMESYNC_MESSAGE` environment variables.
+pub struct CommandSink {
+    command: Vec<String>,
+}
+
+impl Sink for CommandSink {
+    fn send(&self, event: NotifyEvent, message: &str) -> io::Result<()> {
+        let (program, args) = match self.command.split_first() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let status = Command::new(program)
+            .args(args)
+            .arg(message)
+            .env("HOMESYNC_EVENT", event.to_string())
+            .env("HOMESYNC_MESSAGE", message)
+            .stdin(Stdio::null())
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "Notification command exited with {}.",
+                status
+            )))
+        }
+    }
+}
+
+/// Overwrites a file with a JSON description of the most recent event, for
+/// consumption by e.g. status bars.
+pub struct StatusFileSink {
+    path: PathBuf,
+}
+
+impl Sink for StatusFileSink {
+    fn send(&self, event: NotifyEvent, message: &str) -> io::Result<()> {
+        if let Some(p) = self.path.parent() {
+            fs::create_dir_all(p)?;
+        }
+        let status = serde_json::json!({
+            "event": event,
+            "message": message,
+            "time": chrono::Local::now().to_rfc3339(),
+        });
+        fs::write(&self.path, format!("{}\n", status))
+    }
+}
+
+/// Dispatches events the user has subscribed to (via the `notify` section of
+/// the config) to each configured [Sink](trait.Sink.html).
+///
+/// Notifications are best effort. Failing to deliver one is logged but never
+/// interrupts the caller.
+pub struct Notifier {
+    events: HashSet<NotifyEvent>,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Notifier {
+    pub fn new(pc: &PathConfig) -> Self {
+        let mut notifier = Notifier {
+            events: HashSet::new(),
+            sinks: vec![],
+        };
+        let notify = match &pc.config.notify {
+            Some(notify) => notify,
+            None => return notifier,
+        };
+        notifier.events = match &notify.events {
+            Some(events) => events.iter().copied().collect(),
+            None => Notify::DEFAULT_EVENTS.iter().copied().collect(),
+        };
+        if let Some(command) = &notify.command {
+            notifier.sinks.push(Box::new(CommandSink {
+                command: command.clone(),
+            }));
+        }
+        if let Some(status_file) = &notify.status_file {
+            match path::expand(status_file) {
+                Ok(path) => notifier.sinks.push(Box::new(StatusFileSink { path })),
+                Err(e) => warn!(
+                    "Could not expand status file <cyan>{}</>: {}",
+                    status_file.display(),
+                    e
+                ),
+            }
+        }
+        notifier
+    }
+
+    /// Registers an additional sink, e.g. for integrations not expressible in
+    /// the config.
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn notify(&self, event: NotifyEvent, message: &str) {
+        if !self.events.contains(&event) {
+            return;
+        }
+        for sink in &self.sinks {
+            if let Err(e) = sink.send(event, message) {
+                warn!("Could not send <cyan>{}</> notification: {}", event, e);
+            }
+        }
+    }
+}
+
+/// Notifies of the outcome of pushing to the remote.
+pub fn notify_push(pc: &PathConfig, notifier: &Notifier, pushed: &git::Result<()>) {
+    match pushed {
+        Ok(()) => notifier.notify(
+            NotifyEvent::PushSuccess,
+            &format!(
+                "Synced with remote {}.",
+                pc.config.repos.remote.tracking_branch()
+            ),
+        ),
+        Err(e) => {
+            if e.is_conflict() {
+                notifier.notify(NotifyEvent::Conflict, &format!("Conflict on push: {}", e));
+            }
+            notifier.notify(NotifyEvent::PushFailure, &format!("Could not push: {}", e));
+        }
+    }
+}
 
 // ========================================
 // Polling
@@ -28,16 +252,22 @@ enum PollEvent {
     Clear,
 }
 
-fn resolve_pending(tx: &Sender<DebouncedEvent>, pending: &HashSet<PathBuf>) -> Vec<PathBuf> {
+fn resolve_pending(
+    tx: &Sender<DebouncedEvent>,
+    pending: &HashSet<PathBuf>,
+) -> Result<Vec<PathBuf>> {
     let mut to_remove = vec![];
     for path in pending {
-        match path::soft_resolve(&path) {
-            Ok(Some(resolved)) => {
+        match path::normalize(path) {
+            // Report the file under the same path the watcher would.
+            Ok(normalized) if fs::symlink_metadata(normalized.resolved()).is_ok() => {
                 to_remove.push(path.clone());
-                tx.send(DebouncedEvent::Create(resolved.into()))
-                    .expect("File watcher channel closed.");
+                let file = WatchState::watch_target(normalized.resolved())
+                    .map_or_else(|| normalized.resolved().to_path_buf(), |(_, file)| file);
+                tx.send(DebouncedEvent::Create(file))
+                    .map_err(|_| Error::ChannelClosed("file watcher"))?;
             }
-            Ok(None) => (),
+            Ok(_) | Err(path::Error::VarError(_)) | Err(path::Error::UnknownUser(_)) => (),
             Err(e) => {
                 to_remove.push(path.clone());
                 error!(
@@ -48,10 +278,10 @@ fn resolve_pending(tx: &Sender<DebouncedEvent>, pending: &HashSet<PathBuf>) -> V
             }
         }
     }
-    to_remove
+    Ok(to_remove)
 }
 
-fn poll_pending(tx: Sender<DebouncedEvent>, rx: Receiver<PollEvent>, freq_secs: u64) {
+fn poll_pending(tx: Sender<DebouncedEvent>, rx: Receiver<PollEvent>, freq_secs: u64) -> Result<()> {
     let mut pending = HashSet::new();
     loop {
         match rx.try_recv() {
@@ -60,12 +290,83 @@ fn poll_pending(tx: Sender<DebouncedEvent>, rx: Receiver<PollEvent>, freq_secs:
             }
             Ok(PollEvent::Clear) => pending.clear(),
             Err(TryRecvError::Empty) => {
-                resolve_pending(&tx, &pending).iter().for_each(|r| {
+                resolve_pending(&tx, &pending)?.iter().for_each(|r| {
                     pending.remove(r);
                 });
                 thread::sleep(Duration::from_secs(freq_secs));
             }
-            Err(TryRecvError::Disconnected) => panic!("Polling channel closed."),
+            // The daemon has dropped its end of the channel, meaning it is
+            // shutting down.
+            Err(TryRecvError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+// ========================================
+// Pid File
+// ========================================
+
+// Ensures only one daemon manages a given local repository at a time by holding
+// an exclusive `flock` on the pid file for as long as the daemon runs. The file
+// is removed on a clean exit. The lock, however, goes away with the process
+// itself, so a pid file left behind by a daemon that was killed does not get in
+// the way of the next one.
+struct PidFile {
+    path: PathBuf,
+    _file: fs::File,
+}
+
+impl PidFile {
+    fn acquire(pc: &PathConfig) -> Result<Self> {
+        let mut path = path::expand(&pc.config.repos.local).map_err(config::Error::from)?;
+        path.push(".git");
+        if !path.is_dir() {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Local repository not found.",
+            ))?;
+        }
+        path.push("homesync-daemon.pid");
+        loop {
+            let mut file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            // SAFETY: The descriptor stays open for as long as `file` lives.
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    Err(e)?;
+                }
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                Err(Error::AlreadyRunning(contents.trim().parse().unwrap_or(0)))?;
+            }
+            // The previous daemon may have removed the file between us
+            // opening and locking it, in which case we locked a file nobody
+            // else will ever see.
+            let locked = file.metadata()?;
+            match fs::metadata(&path) {
+                Ok(m) if m.dev() == locked.dev() && m.ino() == locked.ino() => (),
+                _ => continue,
+            }
+            file.set_len(0)?;
+            file.write_all(format!("{}\n", process::id()).as_bytes())?;
+            return Ok(PidFile { path, _file: file });
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "Could not remove pid file <cyan>{}</>: {}",
+                self.path.display(),
+                e
+            );
         }
     }
 }
@@ -74,68 +375,204 @@ fn poll_pending(tx: Sender<DebouncedEvent>, rx: Receiver<PollEvent>, freq_secs:
 // File Watcher
 // ========================================
 
+// Many editors (e.g. vim, emacs, VS Code, `sed -i`) save by writing to a
+// temporary file and renaming it over the original. Watches placed on the file
+// itself are lost once the original inode is replaced, so we instead watch the
+// parent directory of each managed file and filter out any events that do not
+// refer to a file we care about.
 struct WatchState<'a> {
     poll_tx: Sender<PollEvent>,
     watcher: &'a mut RecommendedWatcher,
-    watching: HashSet<ResPathBuf>,
+    // The directories currently registered with the underlying watcher.
+    watching: HashSet<PathBuf>,
+    // Fully resolved paths of the files we want to be notified about.
+    managed: HashSet<PathBuf>,
+    // Managed paths that did not exist when last checked.
+    missing: HashSet<PathBuf>,
 }
 
 impl<'a> WatchState<'a> {
-    pub fn new(
-        poll_tx: Sender<PollEvent>,
-        watcher: &'a mut RecommendedWatcher,
-    ) -> notify::Result<Self> {
+    pub fn new(poll_tx: Sender<PollEvent>, watcher: &'a mut RecommendedWatcher) -> Result<Self> {
         Ok(WatchState {
             poll_tx,
             watcher,
             watching: HashSet::new(),
+            managed: HashSet::new(),
+            missing: HashSet::new(),
         })
     }
 
-    fn send_poll(&self, event: PollEvent) {
-        self.poll_tx.send(event).expect("Polling channel closed.");
+    fn send_poll(&self, event: PollEvent) -> Result<()> {
+        self.poll_tx
+            .send(event)
+            .map_err(|_| Error::ChannelClosed("polling"))
     }
 
-    fn watch(&mut self, path: ResPathBuf) {
-        match self.watcher.watch(&path, RecursiveMode::NonRecursive) {
-            Ok(()) => {
-                self.watching.insert(path);
-            }
-            Err(e) => {
-                error!(
-                    "Encountered unexpected error {} when watching path {}",
-                    e,
-                    path.unresolved().display()
-                );
-            }
+    /// Returns whether the given path refers to a file we are managing.
+    pub fn is_managed(&self, path: &Path) -> bool {
+        self.managed.contains(path)
+    }
+
+    /// Marks a managed path as existing, returning whether it was previously
+    /// missing.
+    pub fn resolve(&mut self, path: &Path) -> bool {
+        self.missing.remove(path)
+    }
+
+    /// Marks a managed path as missing.
+    pub fn unresolve(&mut self, path: &Path) {
+        if self.managed.contains(path) {
+            self.missing.insert(path.to_path_buf());
+        }
+    }
+
+    /// Returns whether the given path lives directly within a directory we are
+    /// already watching.
+    pub fn is_watched(&self, path: &Path) -> bool {
+        path.parent().is_some_and(|p| self.watching.contains(p))
+    }
+
+    /// Returns whether the given path is a directory we are watching.
+    pub fn is_watched_dir(&self, path: &Path) -> bool {
+        self.watching.contains(path)
+    }
+
+    /// Determines the file path events will be reported under, along with the
+    /// directory we need to watch to receive them. Only the parent directory is
+    /// canonicalized; the file itself need not exist.
+    fn watch_target(expanded: &Path) -> Option<(PathBuf, PathBuf)> {
+        let name = expanded.file_name()?;
+        let parent = expanded.parent()?.canonicalize().ok()?;
+        if !parent.is_dir() {
+            return None;
         }
+        let mut file = parent.clone();
+        file.push(name);
+        Some((parent, file))
     }
 
     /// Reads in the new path config, updating all watched and pending files
     /// according to the packages in the specified config.
-    pub fn update(&mut self, pc: &PathConfig) {
-        self.send_poll(PollEvent::Clear);
-        for path in &self.watching {
-            match self.watcher.unwatch(&path) {
-                Ok(()) => (),
+    pub fn update(&mut self, pc: &PathConfig) -> Result<()> {
+        self.send_poll(PollEvent::Clear)?;
+
+        // The primary config (along with anything it includes) is always
+        // tracked so that we can hot reload it.
+        let mut directories = HashSet::new();
+        let mut managed = HashSet::new();
+        let config_files = pc.includes.iter().map(|i| i.file.resolved());
+        for config_file in [pc.homesync_yml.resolved()].into_iter().chain(config_files) {
+            if let Some((dir, file)) = WatchState::watch_target(config_file) {
+                directories.insert(dir);
+                managed.insert(file);
+            }
+        }
+        // Files newly matching an include wildcard should be picked up too.
+        for pattern in &pc.include_patterns {
+            if pattern.dir.is_dir() {
+                directories.insert(pattern.dir.clone());
+            }
+        }
+        let mut missing = HashSet::new();
+        let host = pc.host();
+        for (name, package) in &pc.config.packages {
+            // Files mapped elsewhere on this machine are watched there. Like
+            // when staging, a group is mapped (and its symlinks policy looked
+            // up) by its first path.
+            let paths: Vec<(Symlinks, &PathBuf)> = package
+                .groups()
+                .filter_map(|g| Some((g.first()?, g)))
+                .flat_map(|(key, g)| {
+                    let symlinks = pc.symlinks(name, key);
+                    let paths = match host.and_then(|h| h.paths.get(key)) {
+                        Some(mapped) => std::slice::from_ref(mapped),
+                        None => g,
+                    };
+                    paths.iter().map(move |p| (symlinks, p))
+                })
+                .collect();
+            for (symlinks, path) in paths {
+                // Symlinks are watched under their own path rather than that
+                // of their target.
+                let (target, exists) = match path::normalize(path) {
+                    Ok(normalized) => {
+                        let normalized = normalized.resolved();
+                        // Edits made through a followed symlink are reported
+                        // under its target though.
+                        if symlinks == Symlinks::Follow && normalized.is_symlink() {
+                            let followed = normalized
+                                .canonicalize()
+                                .ok()
+                                .and_then(|c| WatchState::watch_target(&c));
+                            if let Some((dir, file)) = followed {
+                                directories.insert(dir);
+                                managed.insert(file);
+                            }
+                        }
+                        (
+                            WatchState::watch_target(normalized),
+                            fs::symlink_metadata(normalized).is_ok(),
+                        )
+                    }
+                    Err(path::Error::VarError(_)) | Err(path::Error::UnknownUser(_)) => {
+                        (None, false)
+                    }
+                    Err(_) => continue,
+                };
+                match target {
+                    Some((dir, file)) => {
+                        directories.insert(dir);
+                        if !exists {
+                            missing.insert(file.clone());
+                        }
+                        managed.insert(file);
+                    }
+                    // Nothing exists for us to watch yet. Defer to polling in
+                    // case the file's directory (or environment variables)
+                    // comes into existence.
+                    None => self.send_poll(PollEvent::Pending(path.clone()))?,
+                }
+            }
+        }
+
+        let stale: Vec<PathBuf> = self.watching.difference(&directories).cloned().collect();
+        for dir in stale {
+            self.watching.remove(&dir);
+            if let Err(e) = self.watcher.unwatch(&dir) {
+                trace!(
+                    "Encountered error {} when unwatching directory {}",
+                    e,
+                    dir.display()
+                );
+            }
+        }
+        for dir in directories {
+            if self.watching.contains(&dir) {
+                continue;
+            }
+            match self.watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    self.watching.insert(dir);
+                }
                 Err(e) => {
                     error!(
-                        "Encountered unexpected error {} when unwatching path {}",
+                        "Encountered unexpected error {} when watching directory {}",
                         e,
-                        path.unresolved().display()
+                        dir.display()
                     );
                 }
             }
         }
-        self.watching.clear();
-        for (_, packages) in &pc.config.packages {
-            for path in packages {
-                match path::soft_resolve(&path) {
-                    Ok(None) => self.send_poll(PollEvent::Pending(path.clone())),
-                    Ok(Some(n)) => self.watch(n),
-                    Err(_) => (),
-                }
-            }
+        self.managed = managed;
+        self.missing = missing;
+        Ok(())
+    }
+
+    /// Forget about a watched directory that has been removed out from under
+    /// us. The next call to `update` will re-arm it if it comes back.
+    pub fn forget(&mut self, dir: &Path) {
+        if self.watching.remove(dir) {
+            let _ = self.watcher.unwatch(dir);
         }
     }
 }
@@ -144,11 +581,98 @@ impl<'a> WatchState<'a> {
 // Daemon
 // ========================================
 
+// How often we wake up to check whether a signal has been received.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+struct Signals {
+    shutdown: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+}
+
+impl Signals {
+    // Signal handlers only set flags, which the daemon checks between events.
+    // This guarantees we never exit in the middle of copying a file. A second
+    // SIGTERM/SIGINT while we are still shutting down exits immediately.
+    fn register() -> Result<Self> {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let reload = Arc::new(AtomicBool::new(false));
+        for signal in [SIGTERM, SIGINT] {
+            flag::register_conditional_shutdown(signal, 1, Arc::clone(&shutdown))?;
+            flag::register(signal, Arc::clone(&shutdown))?;
+        }
+        flag::register(SIGHUP, Arc::clone(&reload))?;
+        Ok(Signals { shutdown, reload })
+    }
+}
+
+fn stage(pc: &PathConfig, notifier: &Notifier) -> Result<()> {
+    // Nobody is around to confirm removals, so only prune if opted into.
+    let prune = if pc.config.prune == Some(true) {
+        copy::Prune::Remove
+    } else {
+        copy::Prune::Keep
+    };
+    if copy::stage(pc, prune)? {
+        notifier.notify(
+            NotifyEvent::Stage,
+            &format!("Staged changes in {}.", pc.config.repos.local.display()),
+        );
+    }
+    Ok(())
+}
+
+fn shutdown(pc: &PathConfig, notifier: &Notifier, commit_on_exit: bool) -> Result<()> {
+    info!("<bold>Stopping:</> Performing final stage.");
+    stage(pc, notifier)?;
+    if commit_on_exit {
+        let mut repo = git::init(pc)?;
+        match git::commit(pc, &mut repo)? {
+            Some(oid) => {
+                notifier.notify(NotifyEvent::Commit, &format!("Committed {}.", oid));
+                // Failing to push leaves the commit for `homesync push` to
+                // sync later, so is no reason to exit uncleanly.
+                let pushed = git::push_branch(pc, &mut repo);
+                notify_push(pc, notifier, &pushed);
+                if let Err(e) = pushed {
+                    error!("Could not push: {}", e);
+                }
+            }
+            None => info!("<bold>Skipped:</> Nothing to commit."),
+        }
+    }
+    info!("<bold>Stopped:</> Daemon exited cleanly.");
+    Ok(())
+}
+
+// Reloads the config, continuing with what we have in memory if it cannot be
+// loaded (e.g. it was saved in a malformed state) rather than taking the daemon
+// down. Returns whether the config was reloaded.
+fn reload(pc: &mut PathConfig, notifier: &mut Notifier, state: &mut WatchState) -> Result<bool> {
+    match config::reload(pc) {
+        Ok(reloaded) => {
+            *pc = reloaded;
+            *notifier = Notifier::new(pc);
+            state.update(pc)?;
+            Ok(true)
+        }
+        Err(e) => {
+            error!("Could not reload config: {}", e);
+            Ok(false)
+        }
+    }
+}
+
 /// Launches a daemon service that monitors changes to files specified in the
 /// config and stages them for changes in the local repository.
 ///
+/// The daemon runs until it receives a SIGTERM or SIGINT, at which point it
+/// performs a final stage (and commit if `commit_on_exit` is set) before
+/// exiting. A SIGHUP forces the config to be reloaded.
+///
 /// Warning! This service is still under development.
-pub fn launch(mut pc: PathConfig, freq_secs: u64) -> Result<(), Box<dyn Error>> {
+pub fn launch(mut pc: PathConfig, freq_secs: u64, commit_on_exit: bool) -> Result<()> {
+    let _pid_file = PidFile::acquire(&pc)?;
+    let signals = Signals::register()?;
     let (poll_tx, poll_rx) = channel();
     let (watch_tx, watch_rx) = channel();
     let watch_tx1 = watch_tx.clone();
@@ -156,85 +680,239 @@ pub fn launch(mut pc: PathConfig, freq_secs: u64) -> Result<(), Box<dyn Error>>
     // watch, but this fails if no file exists at the given path. In these
     // cases, we rely on a basic polling strategy to check if the files ever
     // come into existence.
-    thread::spawn(move || poll_pending(watch_tx, poll_rx, freq_secs));
-    // Track our original config file separately from the other files that may
-    // be defined in the config. We want to make sure we're always alerted on
-    // changes to it for hot reloading purposes, and not worry that our wrapper
-    // will ever clear it from its watch state.
+    let poller = thread::spawn(move || poll_pending(watch_tx, poll_rx, freq_secs));
     let mut watcher: RecommendedWatcher = Watcher::new(watch_tx1, Duration::from_secs(freq_secs))?;
-    watcher.watch(&pc.homesync_yml, RecursiveMode::NonRecursive)?;
     let mut state = WatchState::new(poll_tx, &mut watcher)?;
-    state.update(&pc);
+    state.update(&pc)?;
+    let mut notifier = Notifier::new(&pc);
+    stage(&pc, &notifier)?;
     loop {
-        copy::stage(&pc)?;
-        // Received paths should always be fully resolved.
-        match watch_rx.recv() {
-            Ok(DebouncedEvent::NoticeWrite(p)) => {
-                trace!("<bold>Noticed:</> Write at <cyan>{}</>", p.display());
+        if signals.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        if signals.reload.swap(false, Ordering::SeqCst)
+            && reload(&mut pc, &mut notifier, &mut state)?
+        {
+            stage(&pc, &notifier)?;
+        }
+        if poller.is_finished() {
+            return match poller.join() {
+                Ok(Ok(())) => Err(Error::ChannelClosed("polling")),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(Error::ThreadPanicked("polling")),
+            };
+        }
+        // Received paths should always be fully resolved. Since we watch
+        // directories, we skip any event not referring to a managed file.
+        let event = match watch_rx.recv_timeout(SIGNAL_CHECK_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => Err(Error::ChannelClosed("file watcher"))?,
+        };
+        match event {
+            DebouncedEvent::NoticeWrite(p) => {
+                if state.is_managed(&p) {
+                    trace!("<bold>Noticed:</> Write at <cyan>{}</>", p.display());
+                }
+                continue;
             }
-            Ok(DebouncedEvent::NoticeRemove(p)) => {
-                trace!("<bold>Noticed:</> Removal of <cyan>{}</>", p.display());
+            DebouncedEvent::NoticeRemove(p) => {
+                if state.is_managed(&p) {
+                    trace!("<bold>Noticed:</> Removal of <cyan>{}</>", p.display());
+                }
+                continue;
             }
-            Ok(DebouncedEvent::Create(p)) => {
+            DebouncedEvent::Create(p) => {
+                let mut resolvable = state.resolve(&p);
+                if !state.is_managed(&p) {
+                    // The polling thread reports files whose directories we
+                    // could not watch yet. Pick up the new directory.
+                    if !state.is_watched(&p) {
+                        state.update(&pc)?;
+                        resolvable = true;
+                    }
+                    if !state.is_managed(&p) && !pc.is_config_file(&p) {
+                        continue;
+                    }
+                }
                 trace!("<bold>Created:</> <cyan>{}</>", p.display());
-                if pc.homesync_yml == p {
-                    pc = config::reload(&pc)?;
-                    state.update(&pc);
+                if resolvable {
+                    notifier.notify(
+                        NotifyEvent::Resolvable,
+                        &format!("{} now exists and will be staged.", p.display()),
+                    );
+                }
+                if pc.is_config_file(&p) {
+                    reload(&mut pc, &mut notifier, &mut state)?;
                 }
             }
-            Ok(DebouncedEvent::Write(p)) => {
+            DebouncedEvent::Write(p) => {
+                if !state.is_managed(&p) && !pc.is_config_file(&p) {
+                    continue;
+                }
                 trace!("<bold>Wrote:</> <cyan>{}</>", p.display());
-                if pc.homesync_yml == p {
-                    pc = config::reload(&pc)?;
-                    state.update(&pc);
+                if pc.is_config_file(&p) {
+                    reload(&mut pc, &mut notifier, &mut state)?;
                 }
             }
             // Do not try reloading our primary config in any of the following
             // cases since it may lead to undesired behavior. If our config has
             // e.g. been removed, let's just keep using what we have in memory
             // in the chance it may be added back.
-            Ok(DebouncedEvent::Chmod(p)) => {
+            DebouncedEvent::Chmod(p) => {
+                if !state.is_managed(&p) {
+                    continue;
+                }
                 trace!("<bold>Chmod:</> <cyan>{}</>", p.display());
             }
-            Ok(DebouncedEvent::Remove(p)) => {
-                if pc.homesync_yml == p {
+            DebouncedEvent::Remove(p) => {
+                if state.is_watched_dir(&p) {
+                    // One of the directories we are watching has disappeared.
+                    // Fall back to polling for the files within it.
+                    trace!("<bold>Removed:</> Directory <cyan>{}</>", p.display());
+                    state.forget(&p);
+                    state.update(&pc)?;
+                    continue;
+                } else if !state.is_managed(&p) {
+                    continue;
+                } else if pc.homesync_yml == p {
                     warn!(
                         "<bold>Removed:</> Primary config <cyan>{}</>. Continuing to use last \
                         loaded state",
                         p.display()
                     );
+                    notifier.notify(
+                        NotifyEvent::ConfigRemoved,
+                        &format!("Primary config {} was removed.", p.display()),
+                    );
+                    state.unresolve(&p);
+                } else if pc.is_config_file(&p) {
+                    // An included file going away is not necessarily a mistake
+                    // (e.g. a file matched by a wildcard was deleted), so we
+                    // reload to drop its packages. If the file was explicitly
+                    // included, the reload fails and we keep what we have.
+                    trace!("<bold>Removed:</> Included config <cyan>{}</>", p.display());
+                    reload(&mut pc, &mut notifier, &mut state)?;
                 } else {
+                    state.unresolve(&p);
                     trace!("<bold>Removed:</> <cyan>{}</>", p.display());
                 }
             }
-            Ok(DebouncedEvent::Rename(src, dst)) => {
-                if pc.homesync_yml == src && pc.homesync_yml != dst {
+            DebouncedEvent::Rename(src, dst) => {
+                if !state.is_managed(&src) && !state.is_managed(&dst) {
+                    continue;
+                }
+                trace!(
+                    "<bold>Renamed:</> <cyan>{}</> to <cyan>{}</>.",
+                    src.display(),
+                    dst.display()
+                );
+                // Atomic saves rename a temporary file over the managed one,
+                // which we treat the same as a write.
+                if pc.is_config_file(&dst) || pc.is_config_file(&src) && pc.homesync_yml != src {
+                    reload(&mut pc, &mut notifier, &mut state)?;
+                } else if pc.homesync_yml == src {
                     warn!(
                         "<bold>Renamed:</> Primary config <cyan>{}</>. Continuing from last \
                         loaded state",
                         src.display()
                     );
-                } else {
-                    trace!(
-                        "<bold>Renamed:</> <cyan>{}</> to <cyan>{}</>.",
-                        src.display(),
-                        dst.display()
-                    )
+                    notifier.notify(
+                        NotifyEvent::ConfigRemoved,
+                        &format!("Primary config {} was renamed.", src.display()),
+                    );
                 }
             }
-            Ok(DebouncedEvent::Rescan) => {
+            DebouncedEvent::Rescan => {
                 trace!("Rescanning");
+                state.update(&pc)?;
             }
-            Ok(DebouncedEvent::Error(e, path)) => {
+            DebouncedEvent::Error(e, path) => {
                 warn!(
                     "<bold>Unexpected:</> Error {} at <cyan>{}</>",
                     e,
                     path.unwrap_or_else(|| PathBuf::from("N/A")).display()
                 );
-            }
-            Err(e) => {
-                error!("Watch error: {:?}", e);
+                continue;
             }
         }
+        stage(&pc, &notifier)?;
+    }
+    // Dropping our watch state closes the polling channel, letting the polling
+    // thread exit on its own.
+    drop(state);
+    shutdown(&pc, &notifier, commit_on_exit)
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn pid_file() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir(temp_dir.path().join(".git")).unwrap();
+        let mut config = config::Config::new(config::TEMPLATE).unwrap();
+        config.repos.local = temp_dir.path().to_path_buf();
+        let homesync_yml = temp_dir.path().join("homesync.yml");
+        let pc = PathConfig::new(
+            &path::ResPathBuf::new(&homesync_yml, &homesync_yml).unwrap(),
+            config,
+        );
+
+        let pid_file = PidFile::acquire(&pc).unwrap();
+        match PidFile::acquire(&pc) {
+            Err(Error::AlreadyRunning(pid)) => assert_eq!(pid, process::id()),
+            _ => panic!("Expected the pid file to be locked."),
+        }
+        drop(pid_file);
+        assert!(PidFile::acquire(&pc).is_ok());
+    }
+
+    #[test]
+    fn notify_filters_events() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("status.json");
+        let notifier = Notifier {
+            events: [NotifyEvent::Stage].into_iter().collect(),
+            sinks: vec![Box::new(StatusFileSink { path: path.clone() })],
+        };
+        notifier.notify(NotifyEvent::Commit, "Committed.");
+        assert!(!path.exists());
+        notifier.notify(NotifyEvent::Stage, "Staged.");
+        let status: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(status["event"], "stage");
+        assert_eq!(status["message"], "Staged.");
+    }
+
+    #[test]
+    fn notify_command() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("out");
+        let sink = CommandSink {
+            command: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                format!(
+                    "printf '%s %s' \"$HOMESYNC_EVENT\" \"$1\" > {}",
+                    path.display()
+                ),
+                "sh".to_owned(),
+            ],
+        };
+        sink.send(NotifyEvent::PushFailure, "Could not push.")
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "push_failure Could not push."
+        );
     }
 }
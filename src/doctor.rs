@@ -0,0 +1,721 @@
+//! Diagnostics for a homesync config.
+//!
+//! Deserializing only tells us whether a config is well-formed. Many mistakes
+//! (e.g. a typo in an environment variable, the same file tracked twice, an
+//! SSH key we cannot read) otherwise only surface later as confusing failures
+//! or, worse, silently. [diagnose](fn.diagnose.html) looks for these and
+//! reports each problem along with the line of the config it stems from.
+
+use super::{
+    config,
+    config::{Config, Format, Fragment, Package, PathConfig},
+    git, path,
+    path::ResPathBuf,
+    yaml,
+    yaml::Step,
+};
+use git2::Repository;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+    result,
+};
+
+// ========================================
+// Error
+// ========================================
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(io::Error),
+    InvalidConfig(usize),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IOError(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::InvalidConfig(1) => write!(f, "Found 1 error in config."),
+            Error::InvalidConfig(n) => write!(f, "Found {} errors in config.", n),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// ========================================
+// Diagnostics
+// ========================================
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Homesync works, though likely not as intended.
+    Warning,
+    /// Homesync cannot work as configured.
+    Error,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The config file the problem was found in, i.e. the primary config or
+    /// one of the files it includes.
+    pub file: PathBuf,
+    /// The (1-indexed) line of the config the problem was found on, if known.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+// Where each path tracked so far was found, i.e. the package listing it, the
+// path as written and the file it was written in.
+type Seen = HashMap<PathBuf, (String, PathBuf, PathBuf)>;
+
+// Diagnoses a single config file. Checks that span files (e.g. whether a path
+// is tracked twice) are passed whatever was merged from the others.
+struct Diagnoser<'a> {
+    file: &'a Path,
+    contents: &'a str,
+    format: Format,
+    /// The version the config was written against, prior to any migration.
+    version: u64,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnoser<'_> {
+    fn report(&mut self, severity: Severity, steps: &[Step], message: String) {
+        // JSON is a subset of YAML, but TOML is not.
+        let line = match self.format {
+            Format::Yaml | Format::Json => yaml::locate(self.contents, steps),
+            Format::Toml => None,
+        };
+        self.diagnostics.push(Diagnostic {
+            severity,
+            file: self.file.to_path_buf(),
+            line,
+            message,
+        });
+    }
+
+    fn include(&mut self, err: &config::Error) {
+        match err {
+            // Problems within an included file are reported against it.
+            config::Error::IncludeError(file, e) => self.diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                file: file.clone(),
+                line: e.line(),
+                message: e.to_string(),
+            }),
+            config::Error::MissingInclude(entry) => {
+                let item = entry.to_string_lossy();
+                self.report(
+                    Severity::Error,
+                    &[Step::Key("include"), Step::Item(&item)],
+                    err.to_string(),
+                );
+            }
+            _ => self.report(Severity::Error, &[Step::Key("include")], err.to_string()),
+        }
+    }
+
+    fn packages(
+        &mut self,
+        packages: &BTreeMap<String, Package>,
+        merged: &BTreeMap<String, Package>,
+        seen: &mut Seen,
+    ) {
+        let home = path::expand(Path::new("$HOME")).ok();
+        // Report duplicates against whichever package comes first in the file.
+        let mut packages: Vec<_> = packages.iter().collect();
+        packages.sort_by_key(|(name, _)| {
+            yaml::locate(self.contents, &[Step::Key("packages"), Step::Key(name)])
+        });
+        for (name, package) in packages {
+            let mut entries = vec![];
+            for (index, raw) in package.paths.iter().enumerate() {
+                // Version 1 listed the paths of a package directly.
+                let steps = if self.version == 1 {
+                    vec![Step::Key("packages"), Step::Key(name), Step::Index(index)]
+                } else {
+                    vec![
+                        Step::Key("packages"),
+                        Step::Key(name),
+                        Step::Key("paths"),
+                        Step::Index(index),
+                    ]
+                };
+                entries.push((steps, raw, false));
+            }
+            for (group, alternates) in package.one_of.iter().enumerate() {
+                for (index, raw) in alternates.iter().enumerate() {
+                    let steps = vec![
+                        Step::Key("packages"),
+                        Step::Key(name),
+                        Step::Key("one_of"),
+                        Step::Index(group),
+                        Step::Index(index),
+                    ];
+                    entries.push((steps, raw, true));
+                }
+            }
+            for (steps, raw, alternate) in entries {
+                // Unanchored paths are expanded relative to the working
+                // directory.
+                let anchored = path::is_anchored(raw);
+                if !anchored {
+                    self.report(
+                        Severity::Error,
+                        &steps,
+                        format!(
+                            "Path {} of package {} is relative and so resolves differently \
+                            depending on where homesync is run.",
+                            raw.display(),
+                            name
+                        ),
+                    );
+                }
+                let expanded = match path::expand(raw) {
+                    Ok(expanded) => expanded,
+                    Err(e @ path::Error::SyntaxError(_)) => {
+                        self.report(
+                            Severity::Error,
+                            &steps,
+                            format!(
+                                "Path {} of package {} is malformed: {}",
+                                raw.display(),
+                                name,
+                                e
+                            ),
+                        );
+                        raw.clone()
+                    }
+                    Err(e) => {
+                        // Alternates are expected not to resolve everywhere.
+                        if !alternate {
+                            self.report(
+                                Severity::Warning,
+                                &steps,
+                                format!(
+                                    "Path {} of package {} cannot resolve on this machine: {}.",
+                                    raw.display(),
+                                    name,
+                                    e
+                                ),
+                            );
+                        }
+                        // Still catch the same path being listed twice.
+                        raw.clone()
+                    }
+                };
+                if let Err(e) = fs::metadata(&expanded) {
+                    // Missing files are expected (e.g. on a machine a package
+                    // does not apply to) but a file standing where a directory
+                    // should be is not.
+                    if e.kind() == io::ErrorKind::NotADirectory {
+                        self.report(
+                            Severity::Error,
+                            &steps,
+                            format!(
+                                "Path {} of package {} can never resolve since one of its \
+                                parents is not a directory.",
+                                raw.display(),
+                                name
+                            ),
+                        );
+                    }
+                }
+                // Privileged packages exist to sync files outside of $HOME,
+                // which any of the files listing them may declare.
+                let privileged = merged.get(name).unwrap_or(package).privileged;
+                if let (Some(home), false) = (&home, privileged) {
+                    if anchored && expanded.is_absolute() && !expanded.starts_with(home) {
+                        self.report(
+                            Severity::Warning,
+                            &steps,
+                            format!(
+                                "Path {} of package {} is outside of $HOME.",
+                                raw.display(),
+                                name
+                            ),
+                        );
+                    }
+                }
+                match seen.get(&expanded) {
+                    // Packages split across files are merged, paths and all.
+                    Some((other, _, file)) if other == name && file != self.file => (),
+                    Some((other, other_raw, _)) => self.report(
+                        Severity::Warning,
+                        &steps,
+                        format!(
+                            "Path {} of package {} is already tracked by package {}{}.",
+                            raw.display(),
+                            name,
+                            other,
+                            if other_raw != raw {
+                                format!(" as {}", other_raw.display())
+                            } else {
+                                String::new()
+                            }
+                        ),
+                    ),
+                    None => {
+                        let found = (name.clone(), raw.clone(), self.file.to_path_buf());
+                        seen.insert(expanded, found);
+                    }
+                }
+            }
+        }
+    }
+
+    fn version(&mut self) {
+        if self.version < config::VERSION {
+            let message = format!(
+                "Config is at version {} (the latest is {}). Run `homesync config migrate` \
+                to upgrade it.",
+                self.version,
+                config::VERSION
+            );
+            self.report(Severity::Warning, &[Step::Key("version")], message);
+        }
+    }
+
+    fn unmanaged(&mut self, unmanaged: &HashSet<PathBuf>, packages: &BTreeMap<String, Package>) {
+        let mut unmanaged: Vec<&PathBuf> = unmanaged.iter().collect();
+        unmanaged.sort();
+        for entry in unmanaged {
+            let owner = packages
+                .iter()
+                .find(|(_, package)| package.all_paths().any(|p| p == entry));
+            if let Some((name, _)) = owner {
+                let item = entry.to_string_lossy();
+                self.report(
+                    Severity::Warning,
+                    &[Step::Key("unmanaged"), Step::Item(&item)],
+                    format!(
+                        "Unmanaged path {} is also tracked by package {}.",
+                        entry.display(),
+                        name
+                    ),
+                );
+            }
+        }
+    }
+
+    fn hosts(&mut self, config: &Config) {
+        for (host, settings) in &config.hosts {
+            for path in settings.paths.keys() {
+                let packages = config.packages.values();
+                if packages
+                    .clone()
+                    .any(|p| p.groups().any(|g| g.first() == Some(path)))
+                {
+                    continue;
+                }
+                // Groups are only ever mapped by their first path.
+                let message = if packages.clone().any(|p| p.all_paths().any(|p| p == path)) {
+                    format!(
+                        "Path {} mapped on host {} is an alternate of a one_of group, which \
+                        is only mapped by its first path.",
+                        path.display(),
+                        host
+                    )
+                } else {
+                    format!(
+                        "Path {} mapped on host {} is not tracked by any package.",
+                        path.display(),
+                        host
+                    )
+                };
+                let key = path.to_string_lossy();
+                self.report(
+                    Severity::Warning,
+                    &[
+                        Step::Key("hosts"),
+                        Step::Key(host),
+                        Step::Key("paths"),
+                        Step::Key(&key),
+                    ],
+                    message,
+                );
+            }
+        }
+    }
+
+    fn path_symlinks(
+        &mut self,
+        packages: &BTreeMap<String, Package>,
+        merged: &BTreeMap<String, Package>,
+    ) {
+        for (name, package) in packages {
+            let tracked = merged.get(name).unwrap_or(package);
+            for path in package.path_symlinks.keys() {
+                // Like with hosts, groups are only looked up by their first path.
+                if tracked.groups().any(|g| g.first() == Some(path)) {
+                    continue;
+                }
+                let key = path.to_string_lossy();
+                self.report(
+                    Severity::Warning,
+                    &[
+                        Step::Key("packages"),
+                        Step::Key(name),
+                        Step::Key("path_symlinks"),
+                        Step::Key(&key),
+                    ],
+                    format!(
+                        "Path {} given a symlinks policy is not the first path of any group \
+                        in package {}.",
+                        path.display(),
+                        name
+                    ),
+                );
+            }
+        }
+    }
+
+    fn ssh(&mut self, config: &Config) {
+        let mut keys = vec![("private", &config.ssh.private)];
+        if let Some(public) = &config.ssh.public {
+            keys.push(("public", public));
+        }
+        for (kind, key) in keys {
+            let readable = path::expand(key)
+                .map_err(|e| e.to_string())
+                .and_then(|k| fs::File::open(k).map(|_| ()).map_err(|e| e.to_string()));
+            if let Err(e) = readable {
+                self.report(
+                    Severity::Error,
+                    &[Step::Key("ssh"), Step::Key(kind)],
+                    format!("SSH {} key {} is not readable: {}.", kind, key.display(), e),
+                );
+            }
+        }
+    }
+
+    fn repos(&mut self, pc: &PathConfig, offline: bool) {
+        let local = &pc.config.repos.local;
+        let steps = [Step::Key("repos"), Step::Key("local")];
+        match path::expand(local) {
+            Err(e) => self.report(
+                Severity::Error,
+                &steps,
+                format!(
+                    "Local repository {} cannot resolve: {}.",
+                    local.display(),
+                    e
+                ),
+            ),
+            Ok(expanded) => match Repository::open(&expanded) {
+                Ok(repo) if repo.is_bare() => self.report(
+                    Severity::Error,
+                    &steps,
+                    format!(
+                        "Local repository {} is bare. It should be a working directory.",
+                        local.display()
+                    ),
+                ),
+                Ok(_) => (),
+                // Nonexistent repositories are created on the first pull.
+                Err(_) if !expanded.exists() => (),
+                Err(e) => self.report(
+                    Severity::Error,
+                    &steps,
+                    format!(
+                        "Local repository {} could not be opened: {}.",
+                        local.display(),
+                        e.message()
+                    ),
+                ),
+            },
+        }
+        if !offline {
+            if let Err(e) = git::check_remote(pc) {
+                self.report(
+                    Severity::Error,
+                    &[Step::Key("repos"), Step::Key("remote"), Step::Key("url")],
+                    format!(
+                        "Remote repository {} is unreachable: {}.",
+                        pc.config.repos.remote.url,
+                        e.to_string().trim_end_matches('.')
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Checks the config at the given path, along with any files it includes, for
+/// problems. Diagnostics are ordered by file and then by line. If `offline` is
+/// set, the remote repository is not contacted.
+pub fn diagnose(homesync_yml: &ResPathBuf, offline: bool) -> Result<Vec<Diagnostic>> {
+    let contents = fs::read_to_string(homesync_yml)?;
+    let format = Format::of(homesync_yml.resolved());
+    let (config, version): (Config, u64) = match config::parse_migrated(&contents, format) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Ok(vec![Diagnostic {
+                severity: Severity::Error,
+                file: homesync_yml.unresolved().to_path_buf(),
+                line: e.line(),
+                message: e.to_string(),
+            }])
+        }
+    };
+    let mut pc = PathConfig::new(homesync_yml, config);
+    // Merging folds the included files into the primary config, so keep hold
+    // of what the primary config lists itself.
+    let own_packages = pc.config.packages.clone();
+    let own_unmanaged = pc.config.unmanaged.clone().unwrap_or_default();
+    let included = pc.merge_includes();
+
+    let mut seen = Seen::new();
+    let mut diagnoser = Diagnoser {
+        file: homesync_yml.unresolved(),
+        contents: &contents,
+        format,
+        version,
+        diagnostics: vec![],
+    };
+    if let Err(e) = &included {
+        diagnoser.include(e);
+    }
+    diagnoser.version();
+    diagnoser.packages(&own_packages, &pc.config.packages, &mut seen);
+    diagnoser.path_symlinks(&own_packages, &pc.config.packages);
+    diagnoser.unmanaged(&own_unmanaged, &pc.config.packages);
+    diagnoser.hosts(&pc.config);
+    diagnoser.ssh(&pc.config);
+    diagnoser.repos(&pc, offline);
+    let mut diagnostics = diagnoser.diagnostics;
+    diagnostics.sort_by_key(|d| d.line);
+
+    // Nothing was merged if an include is broken.
+    if included.is_ok() {
+        for included in &pc.includes {
+            let contents = fs::read_to_string(&included.file)?;
+            let format = Format::of(included.file.resolved());
+            let version = config::parse_migrated::<Fragment>(&contents, format)
+                .map(|(_, version)| version)
+                .unwrap_or(config::VERSION);
+            let mut diagnoser = Diagnoser {
+                file: included.file.unresolved(),
+                contents: &contents,
+                format,
+                version,
+                diagnostics: vec![],
+            };
+            diagnoser.version();
+            diagnoser.packages(&included.fragment.packages, &pc.config.packages, &mut seen);
+            diagnoser.path_symlinks(&included.fragment.packages, &pc.config.packages);
+            diagnoser.unmanaged(&included.fragment.unmanaged, &pc.config.packages);
+            let mut found = diagnoser.diagnostics;
+            found.sort_by_key(|d| d.line);
+            diagnostics.extend(found);
+        }
+    }
+    Ok(diagnostics)
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    const CONFIG: &str = "\
+version: 2
+user:
+  name: name
+  email: email@email.com
+ssh:
+  private: $HOME/.ssh/id_ed25519
+repos:
+  local: $HOME/.homesync
+  remote:
+    name: origin
+    branch: master
+    url: https://github.com/owner/repo.git
+unmanaged:
+  - $HOME/.bashrc
+packages:
+  shell:
+    paths:
+      - $HOME/.bashrc
+      - relative/.profile
+  other:
+    paths:
+      - $HOME/.bashrc
+      - /etc/hosts
+      - $UNDEFINED_HOMESYNC_VAR/file
+";
+
+    #[test]
+    #[serial]
+    fn diagnose() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut vars = path::EnvGuard::new();
+        vars.set("HOME", temp_dir.path());
+        vars.remove("UNDEFINED_HOMESYNC_VAR");
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("homesync.yml");
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(CONFIG.as_bytes())
+            .unwrap();
+
+        let homesync_yml = path::resolve(&path).unwrap();
+        let diagnostics = super::diagnose(&homesync_yml, true).unwrap();
+        let found: Vec<(Severity, Option<usize>)> =
+            diagnostics.iter().map(|d| (d.severity, d.line)).collect();
+        assert_eq!(
+            found,
+            vec![
+                // Unreadable SSH key.
+                (Severity::Error, Some(6)),
+                // Unmanaged path also tracked.
+                (Severity::Warning, Some(14)),
+                // Relative path.
+                (Severity::Error, Some(19)),
+                // Tracked twice.
+                (Severity::Warning, Some(22)),
+                // Outside of $HOME.
+                (Severity::Warning, Some(23)),
+                // Undefined variable.
+                (Severity::Warning, Some(24)),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn diagnose_outdated() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut vars = path::EnvGuard::new();
+        vars.set("HOME", temp_dir.path());
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("homesync.yml");
+        let v1 = CONFIG
+            .replace("version: 2\n", "")
+            .replace("    paths:\n", "")
+            .replace("      - ", "    - ");
+        fs::write(&path, v1).unwrap();
+
+        let homesync_yml = path::resolve(&path).unwrap();
+        let diagnostics = super::diagnose(&homesync_yml, true).unwrap();
+        let found: Vec<(Severity, Option<usize>)> =
+            diagnostics.iter().map(|d| (d.severity, d.line)).collect();
+        // Outdated version, which has no line to point to.
+        assert_eq!(found[0], (Severity::Warning, None));
+        // Relative path, located within the original (unmigrated) config.
+        assert!(found.contains(&(Severity::Error, Some(17))));
+    }
+
+    #[test]
+    #[serial]
+    fn diagnose_hosts() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut vars = path::EnvGuard::new();
+        vars.set("HOME", temp_dir.path());
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("homesync.yml");
+        let hosts = "    one_of:
+      - - $HOME/.vimrc
+        - $HOME/.vim/vimrc
+hosts:
+  laptop:
+    paths:
+      $HOME/.bashrc: $HOME/Library/bashrc
+      $HOME/.untracked: $HOME/Library/untracked
+      $HOME/.vimrc: $HOME/Library/vimrc
+      $HOME/.vim/vimrc: $HOME/Library/vimrc
+";
+        fs::write(&path, format!("{}{}", CONFIG, hosts)).unwrap();
+
+        let homesync_yml = path::resolve(&path).unwrap();
+        let diagnostics = super::diagnose(&homesync_yml, true).unwrap();
+        let found: Vec<(Severity, Option<usize>)> =
+            diagnostics.iter().map(|d| (d.severity, d.line)).collect();
+        // Mapped path not tracked by any package.
+        assert!(found.contains(&(Severity::Warning, Some(32))));
+        assert!(!found.iter().any(|(_, line)| *line == Some(31)));
+        // Only the first path of a group is mapped.
+        assert!(!found.iter().any(|(_, line)| *line == Some(33)));
+        assert!(found.contains(&(Severity::Warning, Some(34))));
+    }
+
+    #[test]
+    #[serial]
+    fn diagnose_includes() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut vars = path::EnvGuard::new();
+        vars.set("HOME", temp_dir.path());
+        vars.remove("UNDEFINED_HOMESYNC_VAR");
+        let path = temp_dir.path().join("homesync.yml");
+        let fragment = temp_dir.path().join("shell.yml");
+        let config = CONFIG.replace("unmanaged:\n", "include:\n  - shell.yml\nunmanaged:\n");
+        fs::write(&path, config).unwrap();
+        fs::write(
+            &fragment,
+            "version: 2\npackages:\n  shell:\n    paths:\n      - relative/.inputrc\n      - $HOME/.bashrc\n",
+        )
+        .unwrap();
+        let homesync_yml = path::resolve(&path).unwrap();
+
+        // Problems within an included file are located within it.
+        let diagnostics = super::diagnose(&homesync_yml, true).unwrap();
+        let found: Vec<(&Path, Option<usize>)> = diagnostics
+            .iter()
+            .map(|d| (d.file.as_path(), d.line))
+            .collect();
+        assert!(found.contains(&(path.as_path(), Some(21))));
+        assert!(found.contains(&(fragment.as_path(), Some(5))));
+        // The same package listing a path in both files is not a duplicate.
+        assert!(!found.contains(&(fragment.as_path(), Some(6))));
+
+        // Unparsable includes are reported against the included file.
+        fs::write(&fragment, "version: 2\npackages:\n  shell: [\n").unwrap();
+        let diagnostics = super::diagnose(&homesync_yml, true).unwrap();
+        let broken = diagnostics.iter().find(|d| d.file == fragment).unwrap();
+        assert_eq!(broken.severity, Severity::Error);
+        assert!(broken.line.is_some());
+
+        // Missing includes are reported against their entry.
+        fs::remove_file(&fragment).unwrap();
+        let diagnostics = super::diagnose(&homesync_yml, true).unwrap();
+        let missing = diagnostics
+            .iter()
+            .find(|d| d.message.contains("shell.yml"))
+            .unwrap();
+        assert_eq!(missing.severity, Severity::Error);
+        assert_eq!(
+            (missing.file.as_path(), missing.line),
+            (path.as_path(), Some(14))
+        );
+    }
+
+    #[test]
+    fn diagnose_malformed() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("homesync.yml");
+        fs::write(&path, "user:\n  name: name\n  email: [\n").unwrap();
+        let homesync_yml = path::resolve(&path).unwrap();
+        let diagnostics = super::diagnose(&homesync_yml, true).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert!(diagnostics[0].line.is_some());
+    }
+}
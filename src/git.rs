@@ -5,8 +5,8 @@
 use super::{config::PathConfig, path};
 use git2::{
     BranchType, Commit, Cred, DiffOptions, Direction, FetchOptions, Index, IndexAddOption,
-    ObjectType, PushOptions, Remote, RemoteCallbacks, Repository, Signature, StashApplyOptions,
-    StashFlags,
+    ObjectType, Oid, PushOptions, Remote, RemoteCallbacks, Repository, Signature,
+    StashApplyOptions, StashFlags,
 };
 use simplelog::{info, paris, warn};
 use std::{collections::HashSet, env::VarError, error, fmt, io, path::Path, result};
@@ -22,6 +22,7 @@ pub enum Error {
     GitError(git2::Error),
     IOError(io::Error),
     InvalidBareRepo,
+    PathError(path::Error),
     VarError(VarError),
 }
 
@@ -48,6 +49,7 @@ impl From<path::Error> for Error {
         match err {
             path::Error::IOError(e) => Error::IOError(e),
             path::Error::VarError(e) => Error::VarError(e),
+            e => Error::PathError(e),
         }
     }
 }
@@ -62,6 +64,7 @@ impl fmt::Display for Error {
                 "Local repository should be a working directory. Did you manually initialize with \
                 `--bare`?"
             ),
+            Error::PathError(e) => write!(f, "{}", e),
             Error::VarError(e) => write!(f, "{}", e),
         }
     }
@@ -69,6 +72,20 @@ impl fmt::Display for Error {
 
 impl error::Error for Error {}
 
+impl Error {
+    /// Whether this error was caused by conflicting changes, e.g. when
+    /// rebasing local changes onto the remote.
+    pub fn is_conflict(&self) -> bool {
+        match self {
+            Error::GitError(e) => matches!(
+                e.code(),
+                git2::ErrorCode::Conflict | git2::ErrorCode::MergeConflict
+            ),
+            _ => false,
+        }
+    }
+}
+
 // ========================================
 // Initialization
 // ========================================
@@ -78,7 +95,7 @@ fn clone(pc: &PathConfig, expanded: &Path) -> Result<Repository> {
     let mut builder = git2::build::RepoBuilder::new();
     builder.fetch_options(fetch_options);
 
-    Ok(builder.clone(&pc.config.repos.remote.url, &expanded)?)
+    Ok(builder.clone(&pc.config.repos.remote.url, expanded)?)
 }
 
 // TODO(jrpotter): Setup a sentinel file in the given repository. This is used
@@ -134,21 +151,24 @@ pub fn init(pc: &PathConfig) -> Result<Repository> {
     }
 }
 
+/// Attempts to connect to the remote repository without fetching anything,
+/// e.g. to check the URL and credentials are valid.
+pub fn check_remote(pc: &PathConfig) -> Result<()> {
+    let mut remote = Remote::create_detached(&pc.config.repos.remote.url)?;
+    let callbacks = get_remote_callbacks(pc)?;
+    remote.connect_auth(Direction::Fetch, Some(callbacks), None)?;
+    Ok(())
+}
+
 // ========================================
 // Syncing
 // ========================================
 
-/// Take the current state of the local repository and push changes to the
-/// remote.
+/// Commit all changes currently found in the local repository onto the branch
+/// tracking our remote.
 ///
-/// This method will always pull before pushing to make sure there are no
-/// conflicts that should be resolved.
-pub fn push(pc: &PathConfig, repo: &mut Repository) -> Result<()> {
-    // First pull to make sure there are no conflicts when we push our changes.
-    // This will also perform validation and construct our local and remote
-    // environment.
-    pull(pc, repo)?;
-
+/// Returns the ID of the new commit, or `None` if there was nothing to commit.
+pub fn commit(pc: &PathConfig, repo: &mut Repository) -> Result<Option<Oid>> {
     let refspec = format!("refs/heads/{}", &pc.config.repos.remote.branch);
     repo.set_head(&refspec)?;
 
@@ -157,10 +177,7 @@ pub fn push(pc: &PathConfig, repo: &mut Repository) -> Result<()> {
     // https://git-scm.com/book/en/v2/Git-Internals-Git-Objects
     let mut index = match index_with_all(repo)? {
         Some(index) => index,
-        None => {
-            warn!("Nothing to push. Have you run `homesync stage`?");
-            return Ok(());
-        }
+        None => return Ok(None),
     };
     let index_oid = index.write_tree()?;
     // Want to also reflect this change on the working directory.
@@ -168,7 +185,6 @@ pub fn push(pc: &PathConfig, repo: &mut Repository) -> Result<()> {
     let index_tree = repo.find_tree(index_oid)?;
     info!("<bold>Wrote:</> Index to tree <cyan>{}</>.", index_oid);
 
-    // Commit our changes and push them to our remote.
     // TODO(jrpotter): Come up with a more useful message.
     let signature = now_signature(pc)?;
     let message = "Automated homesync commit.";
@@ -193,6 +209,33 @@ pub fn push(pc: &PathConfig, repo: &mut Repository) -> Result<()> {
     };
     info!("<bold>Commited:</> <cyan>{}</>.", commit_oid);
 
+    Ok(Some(commit_oid))
+}
+
+/// Take the current state of the local repository and push changes to the
+/// remote.
+///
+/// This method will always pull before pushing to make sure there are no
+/// conflicts that should be resolved.
+pub fn push(pc: &PathConfig, repo: &mut Repository) -> Result<()> {
+    // First pull to make sure there are no conflicts when we push our changes.
+    // This will also perform validation and construct our local and remote
+    // environment.
+    pull(pc, repo)?;
+
+    // Commit our changes and push them to our remote.
+    if commit(pc, repo)?.is_none() {
+        warn!("Nothing to push. Have you run `homesync stage`?");
+        return Ok(());
+    }
+    push_branch(pc, repo)
+}
+
+/// Push the branch tracking our remote as is, without pulling or committing
+/// first. The push is rejected if the remote has changes we lack.
+pub fn push_branch(pc: &PathConfig, repo: &mut Repository) -> Result<()> {
+    let refspec = format!("refs/heads/{}", &pc.config.repos.remote.branch);
+
     let mut remote = find_remote(pc, repo)?;
     let call_options = get_remote_callbacks(pc)?;
     remote.connect_auth(Direction::Push, Some(call_options), None)?;
@@ -264,9 +307,7 @@ pub fn pull(pc: &PathConfig, repo: &mut Repository) -> Result<()> {
         .find_branch(&pc.config.repos.remote.branch, BranchType::Local)
         .is_ok()
     {
-        return Ok(with_stash(pc, repo, |pc, repo| {
-            Ok(local_rebase_remote(pc, repo)?)
-        })?);
+        return with_stash(pc, repo, |pc, repo| local_rebase_remote(pc, repo));
     }
 
     // If our local branch does not exist yet, we are likely in an empty git
@@ -318,7 +359,28 @@ pub fn pull(pc: &PathConfig, repo: &mut Repository) -> Result<()> {
         }
     }
 
-    Ok(local_from_remote(pc, repo)?)
+    local_from_remote(pc, repo)
+}
+
+// ========================================
+// Merging
+// ========================================
+
+/// Merges the changes made to a file between the `base` and `theirs` blobs
+/// into the `ours` blob, returning the id of the merged blob. Returns `None` if
+/// the changes conflict.
+pub fn merge_blobs(repo: &Repository, base: Oid, ours: Oid, theirs: Oid) -> Result<Option<Oid>> {
+    // Git only merges trees, so wrap each blob in a tree of its own.
+    let tree = |blob: Oid| -> Result<git2::Tree<'_>> {
+        let mut builder = repo.treebuilder(None)?;
+        builder.insert("file", blob, 0o100644)?;
+        Ok(repo.find_tree(builder.write()?)?)
+    };
+    let index = repo.merge_trees(&tree(base)?, &tree(ours)?, &tree(theirs)?, None)?;
+    if index.has_conflicts() {
+        return Ok(None);
+    }
+    Ok(index.get_path(Path::new("file"), 0).map(|entry| entry.id))
 }
 
 // ========================================
@@ -442,7 +504,7 @@ fn fetch_remote<'repo>(pc: &PathConfig, repo: &'repo Repository) -> Result<Remot
     Ok(remote)
 }
 
-fn get_remote_callbacks(pc: &PathConfig) -> Result<RemoteCallbacks> {
+fn get_remote_callbacks(pc: &PathConfig) -> Result<RemoteCallbacks<'_>> {
     let public_path = match &pc.config.ssh.public {
         Some(p) => Some(path::resolve(p)?),
         None => None,
@@ -462,14 +524,14 @@ fn get_remote_callbacks(pc: &PathConfig) -> Result<RemoteCallbacks> {
     Ok(callbacks)
 }
 
-fn get_fetch_options(pc: &PathConfig) -> Result<FetchOptions> {
+fn get_fetch_options(pc: &PathConfig) -> Result<FetchOptions<'_>> {
     let callbacks = get_remote_callbacks(pc)?;
     let mut fetch_options = FetchOptions::new();
     fetch_options.remote_callbacks(callbacks);
     Ok(fetch_options)
 }
 
-fn get_push_options(pc: &PathConfig) -> Result<PushOptions> {
+fn get_push_options(pc: &PathConfig) -> Result<PushOptions<'_>> {
     let callbacks = get_remote_callbacks(pc)?;
     let mut push_options = PushOptions::new();
     push_options.remote_callbacks(callbacks);
@@ -480,11 +542,11 @@ fn get_push_options(pc: &PathConfig) -> Result<PushOptions> {
 // Utility
 // ========================================
 
-fn now_signature(pc: &PathConfig) -> Result<Signature> {
+fn now_signature(pc: &PathConfig) -> Result<Signature<'_>> {
     Ok(Signature::now(&pc.config.user.name, &pc.config.user.email)?)
 }
 
-fn get_commit_at_head(repo: &Repository) -> Option<Commit> {
+fn get_commit_at_head(repo: &Repository) -> Option<Commit<'_>> {
     let peel = || -> Result<Commit> {
         Ok(repo
             .head()?
@@ -498,11 +560,9 @@ fn get_commit_at_head(repo: &Repository) -> Option<Commit> {
 
 fn temporary_branch_name(pc: &PathConfig, repo: &Repository) -> Result<String> {
     let mut branch_names = HashSet::new();
-    for b in repo.branches(Some(BranchType::Local))? {
-        if let Ok((branch, _branch_type)) = b {
-            if let Some(name) = branch.name()? {
-                branch_names.insert(name.to_owned());
-            }
+    for (branch, _branch_type) in repo.branches(Some(BranchType::Local))?.flatten() {
+        if let Some(name) = branch.name()? {
+            branch_names.insert(name.to_owned());
         }
     }
 
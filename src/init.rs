@@ -0,0 +1,435 @@
+//! Utilities for bootstrapping homesync on a new machine.
+//!
+//! Rather than hand-copying the
+//! [template](https://github.com/jrpotter/homesync/blob/main/rsrc/template.yml)
+//! into place, `homesync init` writes a starter config filled in from what it
+//! can find on the current machine. If pointed at an existing remote, the
+//! remote is cloned and any homesync config already synced there is used
+//! instead.
+
+use super::{
+    config,
+    config::{Config, PathConfig},
+    copy, git, path,
+    path::ResPathBuf,
+    prompt,
+};
+use simplelog::{info, paris, warn};
+use std::{
+    env,
+    env::VarError,
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+    result,
+};
+
+// ========================================
+// Error
+// ========================================
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyExists(PathBuf),
+    ConfigError(config::Error),
+    CopyError(copy::Error),
+    GitError(git::Error),
+    IOError(io::Error),
+    PathError(path::Error),
+    VarError(VarError),
+}
+
+impl From<config::Error> for Error {
+    fn from(err: config::Error) -> Error {
+        Error::ConfigError(err)
+    }
+}
+
+impl From<copy::Error> for Error {
+    fn from(err: copy::Error) -> Error {
+        Error::CopyError(err)
+    }
+}
+
+impl From<git::Error> for Error {
+    fn from(err: git::Error) -> Error {
+        Error::GitError(err)
+    }
+}
+
+impl From<git2::Error> for Error {
+    fn from(err: git2::Error) -> Error {
+        Error::GitError(git::Error::GitError(err))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IOError(err)
+    }
+}
+
+impl From<VarError> for Error {
+    fn from(err: VarError) -> Error {
+        Error::VarError(err)
+    }
+}
+
+impl From<path::Error> for Error {
+    fn from(err: path::Error) -> Error {
+        match err {
+            path::Error::IOError(e) => Error::IOError(e),
+            path::Error::VarError(e) => Error::VarError(e),
+            e => Error::PathError(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::AlreadyExists(p) => write!(
+                f,
+                "A homesync config already exists at {}. Use --force to overwrite it.",
+                p.display()
+            ),
+            Error::ConfigError(e) => write!(f, "{}", e),
+            Error::CopyError(e) => write!(f, "{}", e),
+            Error::GitError(e) => write!(f, "{}", e),
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::PathError(e) => write!(f, "{}", e),
+            Error::VarError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// ========================================
+// Options
+// ========================================
+
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Where to write the config. Defaults to
+    /// [default_target](fn.default_target.html).
+    pub config: Option<PathBuf>,
+    /// The URL of the remote repository to sync with.
+    pub remote: Option<String>,
+    /// Where the local repository should live.
+    pub local: Option<PathBuf>,
+    /// Answer yes to any question we would otherwise prompt for.
+    pub assume_yes: bool,
+    /// Overwrite any config already found.
+    pub force: bool,
+}
+
+/// Private keys we look for in `$HOME/.ssh`, ordered by preference.
+pub const SSH_KEYS: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Where `init` writes the config if no other path is specified, i.e.
+/// `$XDG_CONFIG_HOME/homesync/homesync.yml` or
+/// `$HOME/.config/homesync/homesync.yml` if `$XDG_CONFIG_HOME` is not set.
+pub fn default_target() -> PathBuf {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(xdg) if !xdg.is_empty() => PathBuf::from("$XDG_CONFIG_HOME/homesync/homesync.yml"),
+        _ => PathBuf::from("$HOME/.config/homesync/homesync.yml"),
+    }
+}
+
+// ========================================
+// Discovery
+// ========================================
+
+fn fill_user(config: &mut Config) -> Result<()> {
+    // Prefer whatever the user already told git about themselves.
+    let git_config = git2::Config::open_default()?;
+    let name = git_config
+        .get_string("user.name")
+        .or_else(|_| env::var("USER"))
+        .unwrap_or_else(|_| config.user.name.clone());
+    let email = git_config
+        .get_string("user.email")
+        .unwrap_or_else(|_| config.user.email.clone());
+    config.user.name = prompt::ask("Name to commit as", &name)?;
+    config.user.email = prompt::ask("Email to commit as", &email)?;
+    Ok(())
+}
+
+fn fill_ssh(config: &mut Config) -> Result<()> {
+    for key in SSH_KEYS {
+        let private = PathBuf::from(format!("$HOME/.ssh/{}", key));
+        if let Ok(Some(_)) = path::soft_resolve(&private) {
+            let public = PathBuf::from(format!("$HOME/.ssh/{}.pub", key));
+            config.ssh.public = match path::soft_resolve(&public) {
+                Ok(Some(_)) => Some(public),
+                _ => None,
+            };
+            config.ssh.private = private;
+            info!(
+                "<bold>Found:</> SSH key <cyan>{}</>.",
+                config.ssh.private.display()
+            );
+            return Ok(());
+        }
+    }
+    warn!(
+        "Could not find an SSH key in <cyan>$HOME/.ssh</>. Update the <italic>ssh</> section \
+        of your config before pushing or pulling."
+    );
+    Ok(())
+}
+
+/// Looks through the local repository for a homesync config that was synced
+/// from another machine. Returns the path of the config within the repository
+/// along with the unresolved path it is held under, i.e. the first path of its
+/// `one_of` group if it belongs to one.
+pub fn discover(pc: &PathConfig) -> Result<Option<(PathBuf, PathBuf)>> {
+    let workdir = path::expand(&pc.config.repos.local)?;
+    for candidate in config::default_paths() {
+        let mut repo_file = workdir.clone();
+        repo_file.push(&candidate);
+        if repo_file.is_file() {
+            return Ok(Some((repo_file, candidate)));
+        }
+    }
+    Ok(None)
+}
+
+// ========================================
+// Initialization
+// ========================================
+
+// Where to place a config synced under the given path of the local
+// repository. Like on apply, a `one_of` group held under its first path goes
+// to the first of its alternates that exists, or else the first that expands.
+fn placement(config: &Config, key: &Path) -> PathBuf {
+    let key = key.to_path_buf();
+    let group = config
+        .packages
+        .values()
+        .flat_map(|p| p.groups())
+        .find(|g| g.first() == Some(&key))
+        .unwrap_or_else(|| std::slice::from_ref(&key));
+    group
+        .iter()
+        .find(|c| matches!(path::soft_resolve(c), Ok(Some(_))))
+        .or_else(|| group.iter().find(|c| path::expand(c).is_ok()))
+        .cloned()
+        .unwrap_or_else(default_target)
+}
+
+fn existing_config(target: &Path) -> Option<PathBuf> {
+    let mut candidates = config::default_paths();
+    candidates.push(target.to_path_buf());
+    candidates
+        .into_iter()
+        .find(|c| matches!(path::soft_resolve(c), Ok(Some(_))))
+}
+
+// Points a discovered config at the repository we just cloned, in case it
+// expects the repository elsewhere (e.g. under a different `$HOME`). Returns
+// whether it had to be changed.
+fn keep_clone(pc: &PathConfig, synced: &mut PathConfig) -> bool {
+    let expected = path::expand(&synced.config.repos.local).ok();
+    if expected.is_some() && expected == path::expand(&pc.config.repos.local).ok() {
+        return false;
+    }
+    warn!(
+        "Discovered config expects the local repository at <cyan>{}</> but it was cloned to \
+        <cyan>{}</>. Using the latter.",
+        synced.config.repos.local.display(),
+        pc.config.repos.local.display()
+    );
+    synced.config.repos.local = pc.config.repos.local.clone();
+    true
+}
+
+fn in_memory(target: &Path, config: Config) -> Result<PathConfig> {
+    let expanded = path::expand(target)?;
+    Ok(PathConfig::new(
+        &ResPathBuf::new(&expanded, target)?,
+        config,
+    ))
+}
+
+/// Writes out a new homesync config, cloning the remote repository if one is
+/// specified. Afterward, the user can optionally pull and apply all packages
+/// in one go.
+pub fn init(options: &Options) -> Result<()> {
+    let target = options.config.clone().unwrap_or_else(default_target);
+    if !options.force {
+        if let Some(existing) = existing_config(&target) {
+            Err(Error::AlreadyExists(existing))?;
+        }
+    }
+
+    let mut config = Config::new(config::TEMPLATE)?;
+    fill_user(&mut config)?;
+    fill_ssh(&mut config)?;
+    if let Some(local) = &options.local {
+        config.repos.local = local.clone();
+    }
+    let remote = match &options.remote {
+        Some(remote) => Some(remote.clone()),
+        None => {
+            let remote = prompt::ask("Remote repository URL (leave empty to skip)", "")?;
+            if remote.is_empty() {
+                None
+            } else {
+                Some(remote)
+            }
+        }
+    };
+
+    let mut pc = in_memory(&target, config)?;
+    let mut repo = None;
+    let mut discovered = false;
+    if let Some(remote) = remote {
+        pc.config.repos.remote.url = remote;
+        repo = Some(git::init(&pc)?);
+        // A previously synced config takes precedence over our starter. Place
+        // it like `apply` would unless told otherwise.
+        if let Some((repo_file, unresolved)) = discover(&pc)? {
+            info!(
+                "<bold>Discovered:</> Config <cyan>{}</> in local repository.",
+                unresolved.display()
+            );
+            let contents = fs::read_to_string(&repo_file)?;
+            let parsed = Config::parse(&contents, config::Format::of(&repo_file))?;
+            let unresolved = match &options.config {
+                Some(config) => config.clone(),
+                None => placement(&parsed, &unresolved),
+            };
+            let mut synced = in_memory(&unresolved, parsed)?;
+            let relocated = keep_clone(&pc, &mut synced);
+            if synced.config.repos.remote.url != pc.config.repos.remote.url {
+                warn!(
+                    "Discovered config syncs with remote <cyan>{}</> rather than <cyan>{}</>.",
+                    synced.config.repos.remote.url, pc.config.repos.remote.url
+                );
+            }
+            pc = synced;
+            discovered = true;
+            if let Some(p) = pc.homesync_yml.resolved().parent() {
+                fs::create_dir_all(p)?;
+            }
+            // Keep the synced file byte for byte unless asked to place it
+            // somewhere expecting a different format.
+            if config::Format::of(&repo_file) == config::Format::of(pc.homesync_yml.resolved()) {
+                fs::write(pc.homesync_yml.resolved(), contents)?;
+                if relocated {
+                    pc.write()?;
+                }
+            } else {
+                pc.write()?;
+            }
+        }
+    }
+
+    if !discovered {
+        if let Some(p) = pc.homesync_yml.resolved().parent() {
+            fs::create_dir_all(p)?;
+        }
+        pc.write()?;
+    }
+    info!(
+        "<bold>Initialized:</> Config <cyan>{}</>.",
+        pc.homesync_yml.unresolved().display()
+    );
+
+    if let Some(mut repo) = repo {
+        if options.assume_yes
+            || prompt::confirm("Pull from the remote and apply all packages now?", false)?
+        {
+            git::pull(&pc, &mut repo)?;
+            copy::apply(&pc, None)?;
+        }
+    }
+
+    Ok(())
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Tests must be serial since we are updating our environment variables.
+    use serial_test::serial;
+
+    fn build_pc(dir: &Path, local: &Path) -> PathConfig {
+        let mut config = Config::new(config::TEMPLATE).unwrap();
+        config.repos.local = local.to_path_buf();
+        in_memory(&dir.join("homesync.yml"), config).unwrap()
+    }
+
+    #[test]
+    fn discover() {
+        let temp_dir = TempDir::new().unwrap();
+        let workdir = temp_dir.path().join("repo");
+        let pc = build_pc(temp_dir.path(), &workdir);
+        assert!(super::discover(&pc).unwrap().is_none());
+
+        // Synced configs are found under the path they were synced from.
+        let synced = workdir.join("$HOME/.config/homesync/homesync.yml");
+        fs::create_dir_all(synced.parent().unwrap()).unwrap();
+        fs::write(&synced, config::TEMPLATE).unwrap();
+        let (repo_file, unresolved) = super::discover(&pc).unwrap().unwrap();
+        assert_eq!(repo_file, synced);
+        assert_eq!(
+            unresolved,
+            PathBuf::from("$HOME/.config/homesync/homesync.yml")
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn placement() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut vars = path::EnvGuard::new();
+        vars.set("HOME", temp_dir.path());
+        vars.remove("XDG_CONFIG_HOME");
+        let key = Path::new("$XDG_CONFIG_HOME/homesync/homesync.yml");
+        let mut config = Config::new(config::TEMPLATE).unwrap();
+        config.packages.get_mut("homesync").unwrap().one_of = vec![vec![
+            key.to_path_buf(),
+            PathBuf::from("$HOME/.config/homesync/homesync.yml"),
+            PathBuf::from("$HOME/.homesync.yml"),
+        ]];
+
+        // Groups go to the first alternate that exists, or else expands.
+        assert_eq!(
+            super::placement(&config, key),
+            PathBuf::from("$HOME/.config/homesync/homesync.yml")
+        );
+        fs::write(temp_dir.path().join(".homesync.yml"), "").unwrap();
+        assert_eq!(
+            super::placement(&config, key),
+            PathBuf::from("$HOME/.homesync.yml")
+        );
+        // Anything else is placed where it was synced from, if possible.
+        let other = Path::new("$XDG_CONFIG_HOME/homesync.yml");
+        assert_eq!(super::placement(&config, other), default_target());
+    }
+
+    #[test]
+    fn keep_clone() {
+        let temp_dir = TempDir::new().unwrap();
+        let workdir = temp_dir.path().join("repo");
+        let pc = build_pc(temp_dir.path(), &workdir);
+
+        let mut synced = build_pc(temp_dir.path(), &workdir);
+        assert!(!super::keep_clone(&pc, &mut synced));
+
+        // Configs synced from elsewhere are pointed at the clone.
+        let mut synced = build_pc(temp_dir.path(), &temp_dir.path().join("elsewhere"));
+        assert!(super::keep_clone(&pc, &mut synced));
+        assert_eq!(synced.config.repos.local, workdir);
+    }
+}
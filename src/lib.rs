@@ -15,14 +15,228 @@
 pub mod config;
 pub mod copy;
 pub mod daemon;
+pub mod doctor;
 pub mod git;
+pub mod init;
+pub mod logging;
+pub mod metadata;
 pub mod path;
+pub mod prompt;
+pub mod state;
+pub mod store;
+pub mod systemd;
+pub mod yaml;
 
-use config::PathConfig;
-use std::error::Error;
+use config::{NotifyEvent, PathConfig};
+use paris::formatter::colorize_string;
+use path::ResPathBuf;
+use similar::{ChangeTag, TextDiff};
+use simplelog::{error, info, paris, warn};
+use std::{error::Error, path::PathBuf};
 
 type Result = std::result::Result<(), Box<dyn Error>>;
 
+/// Tracks the specified paths in a package, writing the updated config and
+/// staging the new files immediately.
+///
+/// Paths are stored relative to `$HOME` or `$XDG_CONFIG_HOME` where possible.
+/// Refer to [path::unexpand](path/fn.unexpand.html).
+pub fn run_add(mut config: PathConfig, package: &str, paths: &[PathBuf]) -> Result {
+    let mut unexpanded = vec![];
+    for path in paths {
+        let path = path::unexpand(path)?;
+        for (name, entries) in &config.config.packages {
+            if name != package && entries.all_paths().any(|p| p == &path) {
+                warn!(
+                    "<cyan>{}</> is already tracked by package <cyan>{}</>.",
+                    path.display(),
+                    name
+                );
+            }
+        }
+        unexpanded.push(path);
+    }
+    let added = config.add_paths(package, &unexpanded);
+    if added.is_empty() {
+        warn!("Nothing to add to package <cyan>{}</>.", package);
+        return Ok(());
+    }
+    config.write()?;
+    for path in &added {
+        info!(
+            "<bold>Added:</> <cyan>{}</> to package <cyan>{}</>.",
+            path.display(),
+            package
+        );
+    }
+    copy::stage(&config, copy::Prune::Keep)?;
+    Ok(())
+}
+
+/// Stops tracking the specified paths in a package, or the whole package if
+/// no paths are given, and writes the updated config. If `delete` is set, the
+/// corresponding files are also removed from the local repository.
+pub fn run_rm(mut config: PathConfig, package: &str, paths: &[PathBuf], delete: bool) -> Result {
+    if !config.config.packages.contains_key(package) {
+        warn!("Could not find package <cyan>{}</> in config.", package);
+        return Ok(());
+    }
+    let removed = if paths.is_empty() {
+        config.remove_paths(package, None)
+    } else {
+        let mut unexpanded = vec![];
+        for path in paths {
+            // Accept paths exactly as written in the config as well as paths
+            // on disk.
+            if config.config.packages[package]
+                .all_paths()
+                .any(|p| p == path)
+            {
+                unexpanded.push(path.clone());
+            } else {
+                unexpanded.push(path::unexpand(path)?);
+            }
+        }
+        config.remove_paths(package, Some(&unexpanded))
+    };
+    // Paths pulled in from included files come back the next time the config
+    // is loaded, so leave them be.
+    let (included, removed): (Vec<PathBuf>, Vec<PathBuf>) = removed
+        .into_iter()
+        .partition(|p| config.included_by(package, p).is_some());
+    for path in &included {
+        warn!(
+            "<cyan>{}</> is listed in included file <cyan>{}</>. Remove it there instead.",
+            path.display(),
+            config
+                .included_by(package, path)
+                .unwrap()
+                .unresolved()
+                .display()
+        );
+    }
+    if removed.is_empty() {
+        warn!("Nothing to remove from package <cyan>{}</>.", package);
+        return Ok(());
+    }
+    config.write()?;
+    for path in &removed {
+        info!(
+            "<bold>Removed:</> <cyan>{}</> from package <cyan>{}</>.",
+            path.display(),
+            package
+        );
+    }
+    if delete {
+        copy::unstage(&config, &removed)?;
+    }
+    Ok(())
+}
+
+/// Reports any problems found in the config at the given path. Refer to
+/// [doctor::diagnose](doctor/fn.diagnose.html).
+pub fn run_config_validate(homesync_yml: &ResPathBuf, offline: bool) -> Result {
+    let diagnostics = doctor::diagnose(homesync_yml, offline)?;
+    let mut errors = 0;
+    for diagnostic in &diagnostics {
+        let location = match diagnostic.line {
+            Some(line) => format!("{}:{}", diagnostic.file.display(), line),
+            None => diagnostic.file.display().to_string(),
+        };
+        match diagnostic.severity {
+            doctor::Severity::Warning => warn!("<cyan>{}</> {}", location, diagnostic.message),
+            doctor::Severity::Error => {
+                errors += 1;
+                error!("<cyan>{}</> {}", location, diagnostic.message)
+            }
+        }
+    }
+    if errors > 0 {
+        Err(doctor::Error::InvalidConfig(errors))?;
+    }
+    if diagnostics.is_empty() {
+        info!(
+            "<bold>Validated:</> No problems found in <cyan>{}</>.",
+            homesync_yml.unresolved().display()
+        );
+    }
+    Ok(())
+}
+
+/// Upgrades the config at the given path, along with any files it includes, to
+/// the latest [version](config/constant.VERSION.html). Each change is shown as
+/// a diff and only written once confirmed (or if `assume_yes` is set).
+pub fn run_config_migrate(homesync_yml: &ResPathBuf, assume_yes: bool) -> Result {
+    let pc = config::load(&vec![homesync_yml.clone()])?;
+    let mut files = vec![&pc.homesync_yml];
+    files.extend(pc.includes.iter().map(|i| &i.file));
+    let mut migrated = vec![];
+    for file in files {
+        if let Some((original, upgraded)) = config::migrate_file(file.resolved())? {
+            print_diff(file, &original, &upgraded);
+            migrated.push((file, upgraded));
+        }
+    }
+    if migrated.is_empty() {
+        info!(
+            "<bold>Migrated:</> <cyan>{}</> is already at version {}.",
+            homesync_yml.unresolved().display(),
+            config::VERSION
+        );
+        return Ok(());
+    }
+    if !assume_yes && !prompt::confirm("Write these changes?", false)? {
+        warn!("Left the config untouched.");
+        return Ok(());
+    }
+    for (file, upgraded) in migrated {
+        config::replace_file(file.resolved(), &upgraded)?;
+        info!(
+            "<bold>Migrated:</> <cyan>{}</> to version {}.",
+            file.unresolved().display(),
+            config::VERSION
+        );
+    }
+    Ok(())
+}
+
+fn print_diff(file: &ResPathBuf, original: &str, upgraded: &str) {
+    let name = file.unresolved().display().to_string();
+    let diff = TextDiff::from_lines(original, upgraded);
+    println!("{}", colorize_string(format!("<bold>--- {}</>", name)));
+    println!("{}", colorize_string(format!("<bold>+++ {}</>", name)));
+    for hunk in diff.unified_diff().iter_hunks() {
+        println!("{}", colorize_string(format!("<cyan>{}</>", hunk.header())));
+        for change in hunk.iter_changes() {
+            let line = change.to_string_lossy();
+            let line = line.trim_end_matches('\n');
+            // Colorize around the line itself so nothing in the config is
+            // mistaken for markup.
+            let (sign, color) = match change.tag() {
+                ChangeTag::Delete => ("-", "<red>"),
+                ChangeTag::Insert => ("+", "<green>"),
+                ChangeTag::Equal => {
+                    println!(" {}", line);
+                    continue;
+                }
+            };
+            println!(
+                "{}{}{}{}",
+                colorize_string(color),
+                sign,
+                line,
+                colorize_string("</>")
+            );
+        }
+    }
+}
+
+/// Refer to [config::schema](config/fn.schema.html).
+pub fn run_config_schema() -> Result {
+    println!("{}", config::schema());
+    Ok(())
+}
+
 /// Refer to [copy::apply](copy/fn.apply.html).
 pub fn run_apply(config: PathConfig, package: Option<&str>) -> Result {
     copy::apply(&config, package)?;
@@ -30,8 +244,31 @@ pub fn run_apply(config: PathConfig, package: Option<&str>) -> Result {
 }
 
 /// Refer to [daemon::launch](daemon/fn.launch.html).
-pub fn run_daemon(config: PathConfig, freq_secs: u64) -> Result {
-    daemon::launch(config, freq_secs)?;
+pub fn run_daemon(config: PathConfig, freq_secs: u64, commit_on_exit: bool) -> Result {
+    daemon::launch(config, freq_secs, commit_on_exit)?;
+    Ok(())
+}
+
+/// Refer to [systemd::install](systemd/fn.install.html).
+pub fn run_daemon_install(
+    config: PathConfig,
+    freq_secs: u64,
+    commit_on_exit: bool,
+    timer_secs: Option<u64>,
+) -> Result {
+    systemd::install(&config, freq_secs, commit_on_exit, timer_secs)?;
+    Ok(())
+}
+
+/// Refer to [systemd::uninstall](systemd/fn.uninstall.html).
+pub fn run_daemon_uninstall() -> Result {
+    systemd::uninstall()?;
+    Ok(())
+}
+
+/// Refer to [init::init](init/fn.init.html).
+pub fn run_init(options: init::Options) -> Result {
+    init::init(&options)?;
     Ok(())
 }
 
@@ -43,20 +280,36 @@ pub fn run_list(config: PathConfig) -> Result {
 
 /// Refer to [git::push](git/fn.run_push.html).
 pub fn run_push(config: PathConfig) -> Result {
+    let notifier = daemon::Notifier::new(&config);
     let mut repo = git::init(&config)?;
-    git::push(&config, &mut repo)?;
+    let pushed = git::push(&config, &mut repo);
+    daemon::notify_push(&config, &notifier, &pushed);
+    pushed?;
     Ok(())
 }
 
 /// Refer to [git::pull](git/fn.run_pull.html).
 pub fn run_pull(config: PathConfig) -> Result {
+    let notifier = daemon::Notifier::new(&config);
     let mut repo = git::init(&config)?;
-    git::pull(&config, &mut repo)?;
+    if let Err(e) = git::pull(&config, &mut repo) {
+        if e.is_conflict() {
+            notifier.notify(NotifyEvent::Conflict, &format!("Conflict on pull: {}", e));
+        }
+        Err(e)?;
+    }
     Ok(())
 }
 
-/// Refer to [copy::stage](copy/fn.stage.html).
-pub fn run_stage(config: PathConfig) -> Result {
-    copy::stage(&config)?;
+/// Refer to [copy::stage](copy/fn.stage.html). Stale files are removed if
+/// `prune` is set or the config opts into pruning, and otherwise only after
+/// confirmation.
+pub fn run_stage(config: PathConfig, prune: bool) -> Result {
+    let prune = if prune || config.config.prune == Some(true) {
+        copy::Prune::Remove
+    } else {
+        copy::Prune::Confirm
+    };
+    copy::stage(&config, prune)?;
     Ok(())
 }
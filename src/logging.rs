@@ -0,0 +1,412 @@
+//! Utilities for configuring where and how homesync logs.
+//!
+//! Messages are always written to the terminal. They can additionally be
+//! written to a log file (rotated once it grows too large), either as plain
+//! text or as JSON lines for machine consumption.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use simplelog::{CombinedLogger, SharedLogger, TermLogger};
+use std::{
+    env,
+    env::VarError,
+    error, fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    io::Write,
+    path::{Path, PathBuf},
+    result,
+    str::FromStr,
+    sync::Mutex,
+};
+
+// ========================================
+// Error
+// ========================================
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(io::Error),
+    LoggerError(log::SetLoggerError),
+    VarError(VarError),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IOError(err)
+    }
+}
+
+impl From<log::SetLoggerError> for Error {
+    fn from(err: log::SetLoggerError) -> Error {
+        Error::LoggerError(err)
+    }
+}
+
+impl From<VarError> for Error {
+    fn from(err: VarError) -> Error {
+        Error::VarError(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::LoggerError(e) => write!(f, "{}", e),
+            Error::VarError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// ========================================
+// Options
+// ========================================
+
+/// How each log record is written out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown log format {}.", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub level: LevelFilter,
+    pub file: Option<PathBuf>,
+    pub format: Format,
+}
+
+/// The ordered list of levels `-v` and `-q` step through.
+pub const LEVELS: &[LevelFilter] = &[
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+/// Raises (or lowers) the given level by the number of verbose (or quiet) flags
+/// specified, saturating at either end.
+pub fn adjust_level(level: LevelFilter, verbose: u64, quiet: u64) -> LevelFilter {
+    let index = LEVELS.iter().position(|l| *l == level).unwrap_or(3) as i64;
+    let index = (index + verbose as i64 - quiet as i64).clamp(0, LEVELS.len() as i64 - 1);
+    LEVELS[index as usize]
+}
+
+/// The file a daemonized homesync logs to when none is specified, i.e.
+/// `$XDG_STATE_HOME/homesync/homesync.log`, falling back to
+/// `$HOME/.local/state/homesync/homesync.log`.
+pub fn default_file() -> Result<PathBuf> {
+    let mut path = match env::var("XDG_STATE_HOME") {
+        Ok(state) if !state.is_empty() => PathBuf::from(state),
+        _ => {
+            let mut home = PathBuf::from(env::var("HOME")?);
+            home.push(".local/state");
+            home
+        }
+    };
+    path.push("homesync/homesync.log");
+    Ok(path)
+}
+
+// ========================================
+// Rotation
+// ========================================
+
+/// The size a log file may grow to before it is rotated.
+pub const MAX_FILE_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The number of rotated log files kept around, e.g. `homesync.log.1` through
+/// `homesync.log.3`.
+pub const MAX_ROTATED_FILES: usize = 3;
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        if let Some(p) = path.parent() {
+            fs::create_dir_all(p)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..MAX_ROTATED_FILES).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+// ========================================
+// File Logger
+// ========================================
+
+/// Removes the ANSI escape sequences `paris` inserts for styling. These are
+/// meaningful on a terminal but only noise in a file.
+pub fn strip_ansi(message: &str) -> String {
+    let mut stripped = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Control sequences are terminated by a byte in the range `@` to
+            // `~`, e.g. the `m` in `\x1b[1m`.
+            for t in chars.by_ref() {
+                if t != '[' && ('@'..='~').contains(&t) {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+fn format_record(format: Format, record: &Record) -> String {
+    let now = chrono::Local::now();
+    let message = strip_ansi(&record.args().to_string());
+    match format {
+        Format::Text => format!(
+            "{} [{}] {}\n",
+            now.format("%Y-%m-%d %H:%M:%S%.3f"),
+            record.level(),
+            message
+        ),
+        Format::Json => {
+            let json = serde_json::json!({
+                "time": now.to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": message,
+            });
+            format!("{}\n", json)
+        }
+    }
+}
+
+struct FileLogger {
+    level: LevelFilter,
+    format: Format,
+    file: Mutex<RotatingFile>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_record(self.format, record);
+        if let Ok(mut file) = self.file.lock() {
+            // There is nowhere sensible left to report a failure to log.
+            let _ = file.write_line(&line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+impl SharedLogger for FileLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+// JSON lines written to stderr in place of the usual colored terminal output.
+struct JsonTermLogger {
+    level: LevelFilter,
+}
+
+impl Log for JsonTermLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprint!("{}", format_record(Format::Json, record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for JsonTermLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+// ========================================
+// Initialization
+// ========================================
+
+/// Initializes the global logger according to the specified options.
+///
+/// Only one logger should ever be initialized and it should be done at the
+/// beginning of the program. Otherwise logs are ignored.
+pub fn init(options: &Options) -> Result<()> {
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![];
+    match (&options.file, options.format) {
+        // Machine consumers without a log file still expect JSON somewhere.
+        (None, Format::Json) => loggers.push(Box::new(JsonTermLogger {
+            level: options.level,
+        })),
+        _ => loggers.push(TermLogger::new(
+            options.level,
+            simplelog::Config::default(),
+            simplelog::TerminalMode::Mixed,
+            simplelog::ColorChoice::Auto,
+        )),
+    }
+    if let Some(path) = &options.file {
+        loggers.push(Box::new(FileLogger {
+            level: options.level,
+            format: options.format,
+            file: Mutex::new(RotatingFile::open(path, MAX_FILE_SIZE)?),
+        }));
+    }
+    CombinedLogger::init(loggers)?;
+    Ok(())
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simplelog::paris;
+    use tempfile::TempDir;
+
+    #[test]
+    fn adjust_level() {
+        assert_eq!(
+            super::adjust_level(LevelFilter::Info, 1, 0),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            super::adjust_level(LevelFilter::Info, 5, 0),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            super::adjust_level(LevelFilter::Info, 0, 2),
+            LevelFilter::Error
+        );
+        assert_eq!(
+            super::adjust_level(LevelFilter::Info, 0, 9),
+            LevelFilter::Off
+        );
+    }
+
+    #[test]
+    fn strip_ansi() {
+        let colored = paris::formatter::colorize_string("<bold>Staged:</> <cyan>a</>.");
+        assert_eq!(super::strip_ansi(&colored), "Staged: a.");
+    }
+
+    #[test]
+    fn json_record() {
+        let record = Record::builder()
+            .args(format_args!("Copied \"a\""))
+            .level(log::Level::Info)
+            .target("homesync")
+            .build();
+        let line = format_record(Format::Json, &record);
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["message"], "Copied \"a\"");
+    }
+
+    #[test]
+    fn rotation() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("homesync.log");
+        let mut file = RotatingFile::open(&path, 10).unwrap();
+        for line in ["aaaaaaaa\n", "bbbbbbbb\n", "cccccccc\n"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "cccccccc\n");
+        assert_eq!(fs::read_to_string(file.rotated(1)).unwrap(), "bbbbbbbb\n");
+        assert_eq!(fs::read_to_string(file.rotated(2)).unwrap(), "aaaaaaaa\n");
+    }
+}
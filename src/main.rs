@@ -1,32 +1,9 @@
 use clap::{App, AppSettings, Arg};
-use homesync::path::ResPathBuf;
-use std::{error::Error, io, path::PathBuf};
-use {
-    simplelog,
-    simplelog::{error, paris},
-};
-
-#[cfg(debug_assertions)]
-fn log_level() -> simplelog::LevelFilter {
-    simplelog::LevelFilter::Trace
-}
-
-#[cfg(not(debug_assertions))]
-fn log_level() -> simplelog::LevelFilter {
-    simplelog::LevelFilter::Info
-}
+use homesync::{logging, path::ResPathBuf};
+use simplelog::{error, paris, LevelFilter};
+use std::{error::Error, io, path::PathBuf, process};
 
 fn main() {
-    // Only one logger should ever be initialized and it should be done at the
-    // beginning of the program. Otherwise logs are ignored.
-    simplelog::TermLogger::init(
-        log_level(),
-        simplelog::Config::default(),
-        simplelog::TerminalMode::Mixed,
-        simplelog::ColorChoice::Auto,
-    )
-    .expect("Could not initialize logger library.");
-
     let matches = App::new("homesync")
         .about("Cross desktop sync tool.")
         .version("0.1.0")
@@ -40,6 +17,71 @@ fn main() {
                 .help("Specify a configuration file to use in place of defaults")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("FILE")
+                .help("Additionally write logs to the specified file")
+                .long_help(
+                    "Additionally write logs to the specified file, rotating it once it grows \
+                    too large. When running the daemon, this defaults to \
+                    $XDG_STATE_HOME/homesync/homesync.log.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("The minimum level of messages to log")
+                .possible_values(["off", "error", "warn", "info", "debug", "trace"])
+                .default_value("info")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Write logs as plain text or as JSON lines")
+                .possible_values(["text", "json"])
+                .default_value("text")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Log more messages. May be specified multiple times")
+                .multiple_occurrences(true)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .help("Log fewer messages. May be specified multiple times")
+                .multiple_occurrences(true)
+                .takes_value(false),
+        )
+        .subcommand(
+            App::new("add")
+                .about("Track files in a package and stage them")
+                .arg(
+                    Arg::new("package")
+                        .value_name("PACKAGE")
+                        .help("The package to add the files to, created if necessary")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("paths")
+                        .value_name("PATH")
+                        .help("The files to track")
+                        .required(true)
+                        .multiple_values(true)
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
             App::new("apply")
                 .about("Copy files from local repository to corresponding location")
@@ -59,63 +101,322 @@ fn main() {
                         .takes_value(false),
                 ),
         )
+        .subcommand(
+            App::new("config")
+                .about("Inspect or upgrade the homesync config")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("validate")
+                        .visible_alias("doctor")
+                        .about("Check the config for problems")
+                        .arg(
+                            Arg::new("offline")
+                                .long("offline")
+                                .help("Do not check whether the remote repository is reachable")
+                                .takes_value(false),
+                        ),
+                )
+                .subcommand(
+                    App::new("schema").about("Print a JSON Schema describing the config"),
+                )
+                .subcommand(
+                    App::new("migrate")
+                        .about("Upgrade the config (and included files) to the latest version")
+                        .arg(
+                            Arg::new("yes")
+                                .short('y')
+                                .long("yes")
+                                .help("Write the upgraded config without asking")
+                                .takes_value(false),
+                        ),
+                ),
+        )
         .subcommand(
             App::new("daemon")
                 .about("Start up a new homesync daemon")
+                .args_conflicts_with_subcommands(true)
+                .arg(frequency_arg())
+                .arg(commit_on_exit_arg())
+                .subcommand(
+                    App::new("install")
+                        .about("Write systemd user units running the homesync daemon")
+                        .arg(frequency_arg())
+                        .arg(commit_on_exit_arg())
+                        .arg(
+                            Arg::new("timer")
+                                .long("timer")
+                                .value_name("INTERVAL")
+                                .help("Also install a timer pushing/pulling every INTERVAL seconds")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    App::new("uninstall")
+                        .about("Remove systemd user units written by `homesync daemon install`"),
+                ),
+        )
+        .subcommand(
+            App::new("init")
+                .about("Write a starter config and optionally clone an existing remote")
                 .arg(
-                    Arg::new("frequency")
-                        .short('f')
-                        .long("frequency")
-                        .value_name("FREQUENCY")
-                        .help("How often (in seconds) we poll/debounce file system changes")
+                    Arg::new("remote")
+                        .long("remote")
+                        .value_name("URL")
+                        .help("The remote repository to sync with")
                         .long_help(
-                            "There exists a balance between how responsive changes are \
-                    made and how expensive it is to look for changes. Empirically we found the \
-                    default value to offer a nice compromise but this can be tweaked based on \
-                    preference.",
+                            "The remote repository to sync with. The remote is cloned into the \
+                            local repository and, if it already contains a homesync config, \
+                            that config is used in place of the starter.",
                         )
-                        .takes_value(true)
-                        .default_value("5"),
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("local")
+                        .long("local")
+                        .value_name("DIR")
+                        .help("Where the local repository should live")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .short('y')
+                        .long("yes")
+                        .help("Pull and apply all packages without asking")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Overwrite an existing config")
+                        .takes_value(false),
                 ),
         )
         .subcommand(App::new("list").about("See which packages homesync manages"))
         .subcommand(App::new("pull").about("Pull changes from remote to local"))
         .subcommand(App::new("push").about("Push changes from local to remote"))
         .subcommand(
-            App::new("stage").about("Find all changes and stage them onto the local repository"),
+            App::new("rm")
+                .about("Stop tracking files in a package")
+                .arg(
+                    Arg::new("package")
+                        .value_name("PACKAGE")
+                        .help("The package to remove files from")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("paths")
+                        .value_name("PATH")
+                        .help("The files to stop tracking. If omitted, removes the whole package")
+                        .multiple_values(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("delete")
+                        .long("delete")
+                        .help("Also delete the files from the local repository")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            App::new("stage")
+                .about("Find all changes and stage them onto the local repository")
+                .arg(
+                    Arg::new("prune")
+                        .long("prune")
+                        .help("Remove files no longer referenced by any package without asking")
+                        .takes_value(false),
+                ),
         )
         .get_matches();
 
+    // Only one logger should ever be initialized and it should be done at the
+    // beginning of the program. Otherwise logs are ignored.
+    match log_options(&matches) {
+        Ok(options) => {
+            if let Err(e) = homesync::logging::init(&options) {
+                eprintln!("Could not initialize logger library: {}", e);
+                process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("Could not determine log file: {}", e);
+            process::exit(1);
+        }
+    }
+
     if let Err(e) = dispatch(matches) {
         error!("{}", e);
     }
 }
 
 fn dispatch(matches: clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    // There is no config to load until we've initialized one.
+    if let Some(("init", init)) = matches.subcommand() {
+        return homesync::run_init(homesync::init::Options {
+            config: matches.value_of("config").map(PathBuf::from),
+            remote: init.value_of("remote").map(String::from),
+            local: init.value_of("local").map(PathBuf::from),
+            assume_yes: init.is_present("yes"),
+            force: init.is_present("force"),
+        });
+    }
+    // The schema describes configs in general rather than any one in particular.
+    if let Some(("config", config)) = matches.subcommand() {
+        if let Some(("schema", _)) = config.subcommand() {
+            return homesync::run_config_schema();
+        }
+    }
+    // Removing units should work even if the config no longer loads.
+    if let Some(("daemon", daemon)) = matches.subcommand() {
+        if let Some(("uninstall", _)) = daemon.subcommand() {
+            return homesync::run_daemon_uninstall();
+        }
+    }
     let candidates = find_candidates(&matches)?;
+    // Validation reports malformed configs itself rather than failing to load.
+    if let Some(("config", config)) = matches.subcommand() {
+        return match config.subcommand() {
+            Some(("validate", validate)) => {
+                homesync::run_config_validate(&candidates[0], validate.is_present("offline"))
+            }
+            Some(("migrate", migrate)) => {
+                homesync::run_config_migrate(&candidates[0], migrate.is_present("yes"))
+            }
+            _ => unreachable!(),
+        };
+    }
     let config = homesync::config::load(&candidates)?;
     match matches.subcommand() {
+        Some(("add", matches)) => homesync::run_add(
+            config,
+            matches.value_of("package").unwrap(),
+            &paths_of(matches),
+        ),
         Some(("apply", matches)) => Ok(homesync::run_apply(config, matches.value_of("package"))?),
-        Some(("daemon", matches)) => {
-            let freq_secs: u64 = match matches.value_of("frequency") {
-                Some(f) => f.parse().unwrap_or(0),
-                None => 5,
-            };
-            if freq_secs > 0 {
-                homesync::run_daemon(config, freq_secs)?;
-            } else {
-                error!("Invalid frequency. Expected a positive integer.");
+        Some(("daemon", matches)) => match matches.subcommand() {
+            Some(("install", matches)) => {
+                let timer_secs = match matches.value_of("timer") {
+                    Some(t) => match t.parse::<u64>() {
+                        Ok(t) if t > 0 => Some(t),
+                        _ => {
+                            error!("Invalid timer interval. Expected a positive integer.");
+                            return Ok(());
+                        }
+                    },
+                    None => None,
+                };
+                if let Some(freq_secs) = parse_frequency(matches) {
+                    homesync::run_daemon_install(
+                        config,
+                        freq_secs,
+                        matches.is_present("commit-on-exit"),
+                        timer_secs,
+                    )?;
+                }
+                Ok(())
             }
-            Ok(())
-        }
+            _ => {
+                if let Some(freq_secs) = parse_frequency(matches) {
+                    homesync::run_daemon(config, freq_secs, matches.is_present("commit-on-exit"))?;
+                }
+                Ok(())
+            }
+        },
         Some(("list", _)) => Ok(homesync::run_list(config)?),
         Some(("pull", _)) => Ok(homesync::run_pull(config)?),
         Some(("push", _)) => Ok(homesync::run_push(config)?),
-        Some(("stage", _)) => Ok(homesync::run_stage(config)?),
+        Some(("rm", matches)) => homesync::run_rm(
+            config,
+            matches.value_of("package").unwrap(),
+            &paths_of(matches),
+            matches.is_present("delete"),
+        ),
+        Some(("stage", matches)) => Ok(homesync::run_stage(config, matches.is_present("prune"))?),
         _ => unreachable!(),
     }
 }
 
+fn log_options(matches: &clap::ArgMatches) -> Result<logging::Options, logging::Error> {
+    let level = matches
+        .value_of("log-level")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    let level = logging::adjust_level(
+        level,
+        matches.occurrences_of("verbose"),
+        matches.occurrences_of("quiet"),
+    );
+    let format = matches
+        .value_of("log-format")
+        .and_then(|f| f.parse().ok())
+        .unwrap_or(logging::Format::Text);
+    // A daemon is likely running in the background, where nobody will see its
+    // terminal output.
+    let file = match matches.value_of("log-file") {
+        Some(file) => Some(PathBuf::from(file)),
+        None => match matches.subcommand() {
+            Some(("daemon", daemon)) if daemon.subcommand().is_none() => {
+                Some(logging::default_file()?)
+            }
+            _ => None,
+        },
+    };
+    Ok(logging::Options {
+        level,
+        file,
+        format,
+    })
+}
+
+fn frequency_arg<'a>() -> Arg<'a> {
+    Arg::new("frequency")
+        .short('f')
+        .long("frequency")
+        .value_name("FREQUENCY")
+        .help("How often (in seconds) we poll/debounce file system changes")
+        .long_help(
+            "There exists a balance between how responsive changes are made and how expensive \
+            it is to look for changes. Empirically we found the default value to offer a nice \
+            compromise but this can be tweaked based on preference.",
+        )
+        .takes_value(true)
+        .default_value("5")
+}
+
+fn commit_on_exit_arg<'a>() -> Arg<'a> {
+    Arg::new("commit-on-exit")
+        .long("commit-on-exit")
+        .help("Commit and push staged changes when stopped")
+        .long_help(
+            "On receiving SIGTERM or SIGINT, the daemon always performs a final stage before \
+            exiting. With this flag set, the staged changes are also committed to the local \
+            repository and pushed. The push is not preceded by a pull, so fails (leaving the \
+            commit for `homesync push`) if the remote has changed.",
+        )
+        .takes_value(false)
+}
+
+fn parse_frequency(matches: &clap::ArgMatches) -> Option<u64> {
+    let freq_secs: u64 = match matches.value_of("frequency") {
+        Some(f) => f.parse().unwrap_or(0),
+        None => 5,
+    };
+    if freq_secs > 0 {
+        Some(freq_secs)
+    } else {
+        error!("Invalid frequency. Expected a positive integer.");
+        None
+    }
+}
+
+fn paths_of(matches: &clap::ArgMatches) -> Vec<PathBuf> {
+    matches
+        .values_of("paths")
+        .map(|v| v.map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
 fn find_candidates(matches: &clap::ArgMatches) -> Result<Vec<ResPathBuf>, io::Error> {
     let candidates = match matches.value_of("config") {
         Some(config_match) => vec![PathBuf::from(config_match)],
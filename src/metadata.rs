@@ -0,0 +1,424 @@
+//! Utilities for preserving file metadata git does not track.
+//!
+//! Git only records whether a file is executable, so a private
+//! `~/.ssh/config` would otherwise come out world-readable on the next machine
+//! it is applied to. Instead we record the mode, owner and modification time
+//! of each staged file in a [Manifest](struct.Manifest.html) kept within the
+//! local repository, and restore them on apply.
+//!
+//! Likewise git cannot tell a file deleted on purpose from one that simply was
+//! never staged, so deletions are recorded as
+//! [Tombstones](struct.Tombstones.html).
+
+use super::path;
+use serde_derive::{Deserialize, Serialize};
+use simplelog::{paris, trace, warn};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error,
+    ffi::CString,
+    fmt, fs, io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    result,
+    time::UNIX_EPOCH,
+};
+
+// ========================================
+// Error
+// ========================================
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(io::Error),
+    PathError(path::Error),
+    SerdeError(serde_yaml::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IOError(err)
+    }
+}
+
+impl From<path::Error> for Error {
+    fn from(err: path::Error) -> Error {
+        match err {
+            path::Error::IOError(e) => Error::IOError(e),
+            e => Error::PathError(e),
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(err: serde_yaml::Error) -> Error {
+        Error::SerdeError(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::PathError(e) => write!(f, "{}", e),
+            Error::SerdeError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// ========================================
+// Metadata
+// ========================================
+
+// Modes are written in octal, as they would be passed to `chmod`.
+mod octal {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:04o}", mode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        let mode = String::deserialize(deserializer)?;
+        u32::from_str_radix(&mode, 8).map_err(de::Error::custom)
+    }
+}
+
+/// The metadata of a file we preserve across machines.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Metadata {
+    /// The permission bits of the file, e.g. `0600`.
+    #[serde(with = "octal")]
+    pub mode: u32,
+    /// The name of the user owning the file. Only restored if permitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// The modification time of the file in seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+}
+
+impl Metadata {
+    /// Reads the metadata of the file at the given path, following symlinks.
+    pub fn of(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let owner = path::user_by_uid(metadata.uid())
+            .ok()
+            .flatten()
+            .map(|u| u.name);
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+        Ok(Metadata {
+            mode: metadata.permissions().mode() & 0o7777,
+            owner,
+            mtime,
+        })
+    }
+
+    /// Applies the metadata to the file at the given path, following symlinks.
+    pub fn restore(&self, path: &Path) -> Result<()> {
+        if let Some(mtime) = self.mtime {
+            set_mtime(path, mtime)?;
+        }
+        self.restore_mode(path)?;
+        if let Some(owner) = &self.owner {
+            let current = fs::metadata(path)?.uid();
+            let uid = path::user_by_name(owner)?.map(|u| u.uid);
+            if let Some(uid) = uid.filter(|uid| *uid != current) {
+                // Only privileged users may give away files.
+                if let Err(e) = std::os::unix::fs::chown(path, Some(uid), None) {
+                    trace!(
+                        "Could not change owner of {} to {}: {}",
+                        path.display(),
+                        owner,
+                        e
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies only the mode to the file at the given path, e.g. if its
+    /// contents are already up to date. Nothing is touched if the mode already
+    /// matches.
+    pub fn restore_mode(&self, path: &Path) -> Result<()> {
+        if fs::metadata(path)?.permissions().mode() & 0o7777 != self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the file at the given path likely holds secrets, e.g. SSH keys or
+/// credentials, and so should only be readable by its owner.
+pub fn is_private(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    if name.ends_with(".pub") {
+        return false;
+    }
+    let in_private_dir = path
+        .components()
+        .any(|c| c.as_os_str() == ".ssh" || c.as_os_str() == ".gnupg");
+    in_private_dir
+        || name.starts_with("id_")
+        || name.ends_with(".pem")
+        || name.ends_with(".key")
+        || [".netrc", ".pgpass", ".git-credentials", "credentials"].contains(&name.as_ref())
+}
+
+/// Warns if the file at the given path is private yet world-readable.
+pub fn warn_exposed(path: &Path) {
+    let readable = fs::metadata(path).is_ok_and(|m| m.permissions().mode() & 0o004 != 0);
+    if readable && is_private(path) {
+        warn!(
+            "<cyan>{}</> looks private but is world-readable. Consider running \
+            `<italic>chmod o-r {}</>`.",
+            path.display(),
+            path.display()
+        );
+    }
+}
+
+// ========================================
+// Manifest
+// ========================================
+
+// Sets the modification time of the file at the given path, following
+// symlinks and leaving its access time alone. Unlike opening the file to set
+// it, this works on files we may not write to (e.g. a read-only key) as long
+// as we own them.
+fn set_mtime(path: &Path, mtime: u64) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: mtime as libc::time_t,
+            tv_nsec: 0,
+        },
+    ];
+    // SAFETY: `path` is NUL terminated and `times` holds the two entries
+    // `utimensat` expects.
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Writes the value into the given file of the local repository, leaving it
+// untouched if its contents would not change. Empty values remove the file
+// altogether. Returns whether the file changed.
+fn save<T: serde::Serialize>(file: &Path, value: &T, empty: bool) -> Result<bool> {
+    if empty {
+        return match fs::remove_file(file) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e)?,
+        };
+    }
+    let contents = serde_yaml::to_string(value)?;
+    match fs::read_to_string(file) {
+        Ok(existing) if existing == contents => return Ok(false),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e)?,
+        _ => (),
+    }
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(file, contents)?;
+    Ok(true)
+}
+
+/// Where the [Manifest](struct.Manifest.html) lives, relative to the root of
+/// the local repository.
+pub const MANIFEST: &str = ".homesync/metadata.yml";
+
+/// The [Metadata](struct.Metadata.html) of each staged file, keyed by its
+/// (unresolved) path in the config.
+#[derive(Debug)]
+pub struct Manifest {
+    file: PathBuf,
+    entries: BTreeMap<PathBuf, Metadata>,
+}
+
+impl Manifest {
+    /// Reads the manifest of the local repository at the given directory. A
+    /// missing manifest is considered empty.
+    pub fn load(workdir: &Path) -> Result<Self> {
+        let file = workdir.join(MANIFEST);
+        let entries = match fs::read_to_string(&file) {
+            Ok(contents) => serde_yaml::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => Err(e)?,
+        };
+        Ok(Manifest { file, entries })
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Metadata> {
+        self.entries.get(path)
+    }
+
+    pub fn insert(&mut self, path: &Path, metadata: Metadata) {
+        self.entries.insert(path.to_path_buf(), metadata);
+    }
+
+    /// Drops the entries of any path not satisfying the predicate.
+    pub fn retain<F: Fn(&Path) -> bool>(&mut self, keep: F) {
+        self.entries.retain(|p, _| keep(p));
+    }
+
+    /// Writes the manifest back into the local repository, leaving the file
+    /// untouched if nothing changed. Returns whether the file changed.
+    pub fn save(&self) -> Result<bool> {
+        save(&self.file, &self.entries, self.entries.is_empty())
+    }
+}
+
+// ========================================
+// Tombstones
+// ========================================
+
+/// Where the [Tombstones](struct.Tombstones.html) live, relative to the root of
+/// the local repository.
+pub const TOMBSTONES: &str = ".homesync/deleted.yml";
+
+/// The (unresolved) paths of managed files deleted on some machine, so that
+/// they can be deleted on the others as well.
+#[derive(Debug)]
+pub struct Tombstones {
+    file: PathBuf,
+    paths: BTreeSet<PathBuf>,
+}
+
+impl Tombstones {
+    /// Reads the tombstones of the local repository at the given directory.
+    pub fn load(workdir: &Path) -> Result<Self> {
+        let file = workdir.join(TOMBSTONES);
+        let paths = match fs::read_to_string(&file) {
+            Ok(contents) => serde_yaml::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => Err(e)?,
+        };
+        Ok(Tombstones { file, paths })
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.contains(path)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PathBuf> {
+        self.paths.iter()
+    }
+
+    pub fn insert(&mut self, path: &Path) {
+        self.paths.insert(path.to_path_buf());
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.paths.remove(path);
+    }
+
+    /// Drops any path not satisfying the predicate.
+    pub fn retain<F: Fn(&Path) -> bool>(&mut self, keep: F) {
+        self.paths.retain(|p| keep(p));
+    }
+
+    /// Writes the tombstones back into the local repository, leaving the file
+    /// untouched if nothing changed. Returns whether the file changed.
+    pub fn save(&self) -> Result<bool> {
+        save(&self.file, &self.paths, self.paths.is_empty())
+    }
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn private() {
+        assert!(is_private(Path::new("$HOME/.ssh/config")));
+        assert!(is_private(Path::new("$HOME/.ssh/id_ed25519")));
+        assert!(!is_private(Path::new("$HOME/.ssh/id_ed25519.pub")));
+        assert!(is_private(Path::new("$HOME/.aws/credentials")));
+        assert!(!is_private(Path::new("$HOME/.bashrc")));
+    }
+
+    #[test]
+    fn restore() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "file").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        let metadata = Metadata::of(&file).unwrap();
+        assert_eq!(metadata.mode, 0o644);
+
+        let restored = Metadata {
+            mode: 0o400,
+            owner: metadata.owner.clone(),
+            mtime: Some(1_000_000_000),
+        };
+        restored.restore(&file).unwrap();
+        assert_eq!(Metadata::of(&file).unwrap(), restored);
+
+        // Read-only files still have their modification time restored.
+        let restored = Metadata {
+            mtime: Some(1_500_000_000),
+            ..restored
+        };
+        restored.restore(&file).unwrap();
+        assert_eq!(Metadata::of(&file).unwrap(), restored);
+    }
+
+    #[test]
+    fn manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut manifest = Manifest::load(temp_dir.path()).unwrap();
+        manifest.save().unwrap();
+        assert!(!temp_dir.path().join(MANIFEST).exists());
+
+        let metadata = Metadata {
+            mode: 0o600,
+            owner: None,
+            mtime: None,
+        };
+        manifest.insert(Path::new("$HOME/.ssh/config"), metadata.clone());
+        manifest.insert(Path::new("$HOME/.bashrc"), metadata.clone());
+        manifest.retain(|p| p != Path::new("$HOME/.bashrc"));
+        manifest.save().unwrap();
+        let contents = fs::read_to_string(temp_dir.path().join(MANIFEST)).unwrap();
+        assert!(contents.contains("mode: \"0600\""));
+
+        let manifest = Manifest::load(temp_dir.path()).unwrap();
+        assert_eq!(
+            manifest.get(Path::new("$HOME/.ssh/config")),
+            Some(&metadata)
+        );
+        assert_eq!(manifest.get(Path::new("$HOME/.bashrc")), None);
+
+        let mut manifest = manifest;
+        manifest.retain(|_| false);
+        manifest.save().unwrap();
+        assert!(!temp_dir.path().join(MANIFEST).exists());
+    }
+}
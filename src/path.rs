@@ -9,12 +9,15 @@ use std::{
     env,
     env::VarError,
     error,
-    ffi::OsString,
+    ffi::{CStr, CString},
     fmt,
     hash::{Hash, Hasher},
     io,
+    iter::Peekable,
+    mem,
     path::{Component, Path, PathBuf},
-    result, str,
+    ptr, result, str,
+    str::Chars,
 };
 
 // ========================================
@@ -26,6 +29,8 @@ pub type Result<T> = result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     IOError(io::Error),
+    SyntaxError(String),
+    UnknownUser(String),
     VarError(VarError),
 }
 
@@ -45,6 +50,8 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::IOError(e) => write!(f, "{}", e),
+            Error::SyntaxError(s) => write!(f, "{}", s),
+            Error::UnknownUser(user) => write!(f, "unknown user {}", user),
             Error::VarError(e) => write!(f, "{}", e),
         }
     }
@@ -67,10 +74,10 @@ pub struct ResPathBuf {
 }
 
 fn unresolved_error(path: &Path) -> io::Error {
-    io::Error::new(
-        io::ErrorKind::Other,
-        format!("Path '{}' should be fully resolved.", path.display()),
-    )
+    io::Error::other(format!(
+        "Path '{}' should be fully resolved.",
+        path.display()
+    ))
 }
 
 impl ResPathBuf {
@@ -175,9 +182,8 @@ impl<'de> Visitor<'de> for ResPathBufVisitor {
     where
         E: de::Error,
     {
-        let value = str::from_utf8(v)
-            .map(From::from)
-            .map_err(|_| de::Error::invalid_value(Unexpected::Bytes(v), &self))?;
+        let value =
+            str::from_utf8(v).map_err(|_| de::Error::invalid_value(Unexpected::Bytes(v), &self))?;
         self.visit_str(value)
     }
 
@@ -186,7 +192,6 @@ impl<'de> Visitor<'de> for ResPathBufVisitor {
         E: de::Error,
     {
         let value = String::from_utf8(v)
-            .map(From::from)
             .map_err(|e| de::Error::invalid_value(Unexpected::Bytes(&e.into_bytes()), &self))?;
         self.visit_string(value)
     }
@@ -205,12 +210,202 @@ impl<'de> Deserialize<'de> for ResPathBuf {
 // Resolution
 // ========================================
 
+fn is_var_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// An account as listed in the user database (e.g. `/etc/passwd`).
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub home: String,
+}
+
+// Runs `getpwnam_r` or `getpwuid_r` through `lookup`, growing the buffer the
+// entry is written into until it fits.
+fn lookup_user<F>(lookup: F) -> Result<Option<User>>
+where
+    F: Fn(&mut libc::passwd, &mut [libc::c_char], &mut *mut libc::passwd) -> libc::c_int,
+{
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        // SAFETY: `passwd` is a plain C struct for which all zeroes is valid.
+        let mut entry: libc::passwd = unsafe { mem::zeroed() };
+        let mut found = ptr::null_mut();
+        match lookup(&mut entry, &mut buffer, &mut found) {
+            libc::ERANGE => {
+                let len = buffer.len() * 2;
+                buffer.resize(len, 0);
+            }
+            0 if found.is_null() => return Ok(None),
+            // SAFETY: On success the strings of `entry` point into `buffer`
+            // and are NUL terminated.
+            0 => unsafe {
+                return Ok(Some(User {
+                    name: CStr::from_ptr(entry.pw_name).to_string_lossy().into_owned(),
+                    uid: entry.pw_uid,
+                    home: CStr::from_ptr(entry.pw_dir).to_string_lossy().into_owned(),
+                }));
+            },
+            e => return Err(io::Error::from_raw_os_error(e).into()),
+        }
+    }
+}
+
+/// The account with the given name, if any.
+pub fn user_by_name(name: &str) -> Result<Option<User>> {
+    let name = match CString::new(name) {
+        Ok(name) => name,
+        Err(_) => return Ok(None),
+    };
+    lookup_user(|entry, buffer, found| unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            found,
+        )
+    })
+}
+
+/// The account with the given user id, if any.
+pub fn user_by_uid(uid: u32) -> Result<Option<User>> {
+    lookup_user(|entry, buffer, found| unsafe {
+        libc::getpwuid_r(uid, entry, buffer.as_mut_ptr(), buffer.len(), found)
+    })
+}
+
+// The home directory of the given user.
+fn user_home(user: &str) -> Result<String> {
+    user_by_name(user)?
+        .map(|u| u.home)
+        .ok_or_else(|| Error::UnknownUser(user.to_owned()))
+}
+
+// Expands a single variable reference, i.e. whatever follows a `$`.
+fn expand_var(chars: &mut Peekable<Chars>) -> Result<String> {
+    if chars.next_if_eq(&'{').is_none() {
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| is_var_char(*c)) {
+            name.push(c);
+        }
+        if name.is_empty() {
+            Err(Error::SyntaxError(
+                "Expected a variable name after $. Use $$ for a literal $.".to_owned(),
+            ))?;
+        }
+        return var(&name);
+    }
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|c| is_var_char(*c)) {
+        name.push(c);
+    }
+    let default = if chars.next_if_eq(&':').is_some() {
+        if chars.next_if_eq(&'-').is_none() {
+            Err(Error::SyntaxError(format!(
+                "Expected :- after ${{{}.",
+                name
+            )))?;
+        }
+        // The default may itself contain (braced) variables.
+        let mut default = String::new();
+        let mut depth = 0;
+        loop {
+            match chars.next() {
+                Some('}') if depth == 0 => break,
+                Some(c) => {
+                    if c == '{' {
+                        depth += 1;
+                    } else if c == '}' {
+                        depth -= 1;
+                    }
+                    default.push(c);
+                }
+                None => Err(Error::SyntaxError(format!("Missing }} after ${{{}.", name)))?,
+            }
+        }
+        Some(default)
+    } else {
+        if chars.next_if_eq(&'}').is_none() {
+            Err(Error::SyntaxError(format!("Missing }} after ${{{}.", name)))?;
+        }
+        None
+    };
+    if name.is_empty() {
+        Err(Error::SyntaxError(
+            "Expected a variable name after ${.".to_owned(),
+        ))?;
+    }
+    match (var(&name), default) {
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(default)) => expand_str(&default),
+        (Err(e), None) => Err(e),
+    }
+}
+
+// Looks up an environment variable, treating empty ones as unset like e.g. the
+// XDG base directory specification does. Otherwise `$XDG_CONFIG_HOME/foo`
+// would expand to `/foo`.
+fn var(name: &str) -> Result<String> {
+    match env::var(name)? {
+        value if value.is_empty() => Err(VarError::NotPresent)?,
+        value => Ok(value),
+    }
+}
+
+// Expands `~` and any environment variables found in the string.
+fn expand_str(raw: &str) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = raw;
+    if let Some(tilde) = raw.strip_prefix('~') {
+        let (user, after) = tilde.split_at(tilde.find('/').unwrap_or(tilde.len()));
+        if user.is_empty() {
+            expanded.push_str(&env::var("HOME")?);
+        } else {
+            expanded.push_str(&user_home(user)?);
+        }
+        rest = after;
+    }
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+        } else if chars.next_if_eq(&'$').is_some() {
+            expanded.push('$');
+        } else {
+            expanded.push_str(&expand_var(&mut chars)?);
+        }
+    }
+    Ok(expanded)
+}
+
+/// Whether the provided path is independent of the current working directory,
+/// i.e. it is absolute or begins with `~` or an environment variable.
+pub fn is_anchored(path: &Path) -> bool {
+    let lossy = path.to_string_lossy();
+    path.is_absolute()
+        || lossy.starts_with('~')
+        || (lossy.starts_with('$') && !lossy.starts_with("$$"))
+}
+
 /// Find environment variables within the argument and expand them if possible.
 ///
-/// Returns an error if any found environment variables are not defined.
+/// Variables may be written as `$VAR` or `${VAR}` anywhere in the path, and
+/// `${VAR:-default}` falls back to `default` if `VAR` is unset or empty. A
+/// leading `~` or `~user` refers to the (given user's) home directory, and `$$`
+/// stands for a literal `$`.
+///
+/// Returns an error if any found environment variables are not defined, or
+/// are empty.
 pub fn expand(path: &Path) -> Result<PathBuf> {
+    // Paths that are not valid unicode cannot contain anything to expand.
+    let substituted = match path.to_str() {
+        Some(raw) => PathBuf::from(expand_str(raw)?),
+        None => path.to_path_buf(),
+    };
     let mut expanded = env::current_dir()?;
-    for comp in path.components() {
+    for comp in substituted.components() {
         match comp {
             Component::Prefix(_) => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -229,24 +424,28 @@ pub fn expand(path: &Path) -> Result<PathBuf> {
                     ))?
                 }
             }
-            Component::Normal(c) => {
-                let lossy = c.to_string_lossy();
-                if lossy.starts_with("$") {
-                    let evar = env::var(lossy.replacen("$", "", 1))?;
-                    expanded.push(Component::Normal(&OsString::from(evar)));
-                } else {
-                    expanded.push(c);
-                }
-            }
+            Component::Normal(c) => expanded.push(c),
         }
     }
     Ok(expanded)
 }
 
+/// Normalize the provided path lexically, expanding environment variables and
+/// any `.` or `..` components without consulting the file system. Unlike
+/// [resolve](fn.resolve.html), the file need not exist and symlinks are left
+/// as is, e.g. a symlinked `$HOME/.bashrc` stays `/home/owner/.bashrc` rather
+/// than becoming the path of its target.
+pub fn normalize(path: &Path) -> Result<ResPathBuf> {
+    Ok(ResPathBuf {
+        inner: expand(path)?,
+        unresolved: path.to_path_buf(),
+    })
+}
+
 /// Attempt to resolve the provided path, returning a fully resolved path
 /// instance if successful.
 pub fn resolve(path: &Path) -> Result<ResPathBuf> {
-    let resolved = expand(&path)?;
+    let resolved = expand(path)?;
     let resolved = resolved.canonicalize()?;
     Ok(ResPathBuf {
         inner: resolved,
@@ -265,9 +464,94 @@ pub fn soft_resolve(path: &Path) -> Result<Option<ResPathBuf>> {
         Ok(resolved) => Ok(Some(resolved)),
         Err(Error::IOError(e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
         Err(e @ Error::IOError(_)) => Err(e),
-        // An ENV variable isn't defined yet, but we assume its possible it'll
-        // be defined in the future. Don't report as an error.
-        Err(Error::VarError(_)) => Ok(None),
+        Err(e @ Error::SyntaxError(_)) => Err(e),
+        // An ENV variable (or user) isn't defined yet, but we assume its
+        // possible it'll be defined in the future. Don't report as an error.
+        Err(Error::VarError(_)) | Err(Error::UnknownUser(_)) => Ok(None),
+    }
+}
+
+/// The environment variables [unexpand](fn.unexpand.html) substitutes back
+/// into paths.
+pub const UNEXPAND_VARS: &[&str] = &["XDG_CONFIG_HOME", "HOME"];
+
+/// The inverse of [expand](fn.expand.html). Rewrites the provided path relative
+/// to whichever of [UNEXPAND_VARS](constant.UNEXPAND_VARS.html) is its longest
+/// prefix, e.g. `/home/owner/.bashrc` becomes `$HOME/.bashrc`.
+///
+/// Relative paths are first made absolute with respect to the current working
+/// directory. Paths not found under any of these variables are returned as is.
+pub fn unexpand(path: &Path) -> Result<PathBuf> {
+    // Any `$` left over is part of a file name and must not be expanded again.
+    let escape = |p: &Path| match p.to_str() {
+        Some(p) => PathBuf::from(p.replace('$', "$$")),
+        None => p.to_path_buf(),
+    };
+    let expanded = expand(path)?;
+    let mut best: Option<(usize, PathBuf)> = None;
+    for var in UNEXPAND_VARS {
+        let value = match env::var(var) {
+            Ok(value) if !value.is_empty() => PathBuf::from(value),
+            _ => continue,
+        };
+        if let Ok(rest) = expanded.strip_prefix(&value) {
+            let depth = value.components().count();
+            if best.as_ref().is_none_or(|(d, _)| depth > *d) {
+                let mut unexpanded = PathBuf::from(format!("${}", var));
+                if !rest.as_os_str().is_empty() {
+                    unexpanded.push(escape(rest));
+                }
+                best = Some((depth, unexpanded));
+            }
+        }
+    }
+    Ok(best.map_or_else(|| escape(&expanded), |(_, p)| p))
+}
+
+// ========================================
+// Environment
+// ========================================
+
+/// Sets environment variables for as long as it is alive, restoring their
+/// previous values once dropped. Tests using it should still be `#[serial]`.
+#[cfg(test)]
+#[derive(Default)]
+pub struct EnvGuard {
+    saved: Vec<(String, Option<std::ffi::OsString>)>,
+}
+
+#[cfg(test)]
+impl EnvGuard {
+    pub fn new() -> Self {
+        EnvGuard::default()
+    }
+
+    fn save(&mut self, key: &str) {
+        if !self.saved.iter().any(|(k, _)| k == key) {
+            self.saved.push((key.to_owned(), env::var_os(key)));
+        }
+    }
+
+    pub fn set<V: AsRef<std::ffi::OsStr>>(&mut self, key: &str, value: V) {
+        self.save(key);
+        env::set_var(key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.save(key);
+        env::remove_var(key);
+    }
+}
+
+#[cfg(test)]
+impl Drop for EnvGuard {
+    fn drop(&mut self) {
+        for (key, value) in self.saved.drain(..).rev() {
+            match value {
+                Some(value) => env::set_var(key, value),
+                None => env::remove_var(key),
+            }
+        }
     }
 }
 
@@ -278,9 +562,12 @@ pub fn soft_resolve(path: &Path) -> Result<Option<ResPathBuf>> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashSet;
+    use std::{collections::HashSet, fs};
     use tempfile::NamedTempFile;
 
+    // Tests must be serial since we are updating our environment variables.
+    use serial_test::serial;
+
     #[test]
     fn respath_absolute() {
         let abs = Path::new("/home/jrpotter/example");
@@ -318,12 +605,104 @@ mod tests {
     }
 
     #[test]
+    #[serial]
     fn expand_component() {
-        env::set_var("EXAMPLE", "example");
+        let mut vars = EnvGuard::new();
+        vars.set("EXAMPLE", "example");
         let expanded = expand(Path::new("/a/b/$EXAMPLE/c")).unwrap();
         assert_eq!(Path::new("/a/b/example/c"), expanded);
         let expanded = expand(Path::new("/a/b/pre$EXAMPLE/c")).unwrap();
-        assert_eq!(Path::new("/a/b/pre$EXAMPLE/c"), expanded);
+        assert_eq!(Path::new("/a/b/preexample/c"), expanded);
+        let expanded = expand(Path::new("/a/b/$EXAMPLE.d/c")).unwrap();
+        assert_eq!(Path::new("/a/b/example.d/c"), expanded);
+        let expanded = expand(Path::new("/a/b/${EXAMPLE}post/c")).unwrap();
+        assert_eq!(Path::new("/a/b/examplepost/c"), expanded);
+        let expanded = expand(Path::new("/a/b/$$EXAMPLE/c")).unwrap();
+        assert_eq!(Path::new("/a/b/$EXAMPLE/c"), expanded);
+    }
+
+    #[test]
+    #[serial]
+    fn expand_default() {
+        let mut vars = EnvGuard::new();
+        vars.set("HOME", "/home/owner");
+        vars.remove("XDG_CONFIG_HOME");
+        let path = Path::new("${XDG_CONFIG_HOME:-$HOME/.config}/nvim");
+        assert_eq!(Path::new("/home/owner/.config/nvim"), expand(path).unwrap());
+        vars.set("XDG_CONFIG_HOME", "");
+        assert_eq!(Path::new("/home/owner/.config/nvim"), expand(path).unwrap());
+        vars.set("XDG_CONFIG_HOME", "/xdg");
+        assert_eq!(Path::new("/xdg/nvim"), expand(path).unwrap());
+        let nested = Path::new("${UNDEFINED_HOMESYNC_VAR:-${HOME}/a}/b");
+        assert_eq!(Path::new("/home/owner/a/b"), expand(nested).unwrap());
+        assert!(matches!(
+            expand(Path::new("${HOME/a")),
+            Err(Error::SyntaxError(_))
+        ));
+        assert!(matches!(
+            expand(Path::new("/a/$/b")),
+            Err(Error::SyntaxError(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn expand_tilde() {
+        let mut vars = EnvGuard::new();
+        vars.set("HOME", "/home/owner");
+        assert_eq!(Path::new("/home/owner"), expand(Path::new("~")).unwrap());
+        assert_eq!(
+            Path::new("/home/owner/.bashrc"),
+            expand(Path::new("~/.bashrc")).unwrap()
+        );
+        assert_eq!(Path::new("/a/~/b"), expand(Path::new("/a/~/b")).unwrap());
+        let root = user_by_name("root").unwrap().unwrap();
+        assert_eq!(
+            Path::new(&root.home).join(".bashrc"),
+            expand(Path::new("~root/.bashrc")).unwrap()
+        );
+        assert!(matches!(
+            expand(Path::new("~undefined-homesync-user/.bashrc")),
+            Err(Error::UnknownUser(_))
+        ));
+    }
+
+    #[test]
+    fn users() {
+        // Not every system keeps root's home at `/root`, so ask libc directly.
+        // SAFETY: The entry is copied out before anything else may overwrite
+        // the static buffer `getpwnam` returns.
+        let home = unsafe {
+            let entry = libc::getpwnam(c"root".as_ptr());
+            assert!(!entry.is_null());
+            CStr::from_ptr((*entry).pw_dir)
+                .to_string_lossy()
+                .into_owned()
+        };
+        let root = user_by_name("root").unwrap().unwrap();
+        assert_eq!((root.uid, root.home.as_str()), (0, home.as_str()));
+        assert_eq!(user_by_uid(0).unwrap().unwrap().name, "root");
+        assert!(user_by_name("undefined-homesync-user").unwrap().is_none());
+        assert!(user_by_name("nul\0").unwrap().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn unexpand() {
+        let mut vars = EnvGuard::new();
+        vars.set("HOME", "/home/owner");
+        vars.set("XDG_CONFIG_HOME", "/home/owner/.config");
+        let unexpanded = super::unexpand(Path::new("/home/owner/.bashrc")).unwrap();
+        assert_eq!(Path::new("$HOME/.bashrc"), unexpanded);
+        let unexpanded = super::unexpand(Path::new("/home/owner/.config/nvim/init.vim")).unwrap();
+        assert_eq!(Path::new("$XDG_CONFIG_HOME/nvim/init.vim"), unexpanded);
+        let unexpanded = super::unexpand(Path::new("/etc/hosts")).unwrap();
+        assert_eq!(Path::new("/etc/hosts"), unexpanded);
+        let unexpanded = super::unexpand(Path::new("/home/owner/$$money")).unwrap();
+        assert_eq!(Path::new("$HOME/$$money"), unexpanded);
+        vars.set("XDG_CONFIG_HOME", "");
+        let unexpanded = super::unexpand(Path::new("/home/owner/.config/nvim/init.vim")).unwrap();
+        assert_eq!(Path::new("$HOME/.config/nvim/init.vim"), unexpanded);
     }
 
     #[test]
@@ -337,6 +716,19 @@ mod tests {
         assert!(super::resolve(&path).is_err());
     }
 
+    #[test]
+    fn normalize() {
+        let temp = NamedTempFile::new().unwrap();
+        let link = PathBuf::from(format!("{}-link", temp.path().display()));
+        std::os::unix::fs::symlink(temp.path(), &link).unwrap();
+        let normalized = super::normalize(&link.join("../a/./b")).unwrap();
+        assert_eq!(normalized.resolved(), &link.parent().unwrap().join("a/b"));
+        let normalized = super::normalize(&link).unwrap();
+        assert_eq!(normalized.resolved(), &link);
+        assert_ne!(super::resolve(&link).unwrap(), normalized);
+        fs::remove_file(&link).unwrap();
+    }
+
     #[test]
     fn soft_resolve() {
         let path: PathBuf;
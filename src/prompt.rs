@@ -0,0 +1,69 @@
+//! Utilities for asking the user questions on the terminal.
+//!
+//! Homesync may be run non-interactively (e.g. from the daemon or a systemd
+//! timer). In these cases nobody is around to answer, so every prompt falls
+//! back to its default answer when stdin is not a terminal.
+
+use std::{
+    io,
+    io::{BufRead, IsTerminal, Write},
+};
+
+/// Whether we are able to ask the user anything.
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal()
+}
+
+/// Asks the user a free-form question, returning `default` if they do not
+/// answer or if we are not running interactively.
+pub fn ask(question: &str, default: &str) -> io::Result<String> {
+    if !is_interactive() {
+        return Ok(default.to_owned());
+    }
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    if answer.is_empty() {
+        Ok(default.to_owned())
+    } else {
+        Ok(answer.to_owned())
+    }
+}
+
+/// Asks the user a yes/no question, returning `default` if they do not answer
+/// or if we are not running interactively.
+pub fn confirm(question: &str, default: bool) -> io::Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    let answer = ask(&format!("{} ({})", question, hint), "")?;
+    Ok(match answer.to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    })
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        // There is nobody to answer when run without a terminal, e.g. in CI.
+        if is_interactive() {
+            return;
+        }
+        assert_eq!(ask("Name", "owner").unwrap(), "owner");
+        assert!(confirm("Continue?", true).unwrap());
+        assert!(!confirm("Continue?", false).unwrap());
+    }
+}
@@ -0,0 +1,310 @@
+//! State homesync keeps about the current machine.
+//!
+//! Unlike the [metadata manifest](../metadata/index.html), none of this is
+//! meant to be synced. It lives within the `.git` directory of the local
+//! repository where git never picks it up.
+
+use git2::{ObjectType, Oid};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error, fmt, fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    result,
+    time::UNIX_EPOCH,
+};
+
+// ========================================
+// Error
+// ========================================
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    GitError(git2::Error),
+    IOError(io::Error),
+    SerdeError(serde_json::Error),
+}
+
+impl From<git2::Error> for Error {
+    fn from(err: git2::Error) -> Error {
+        Error::GitError(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IOError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::SerdeError(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::GitError(e) => write!(f, "{}", e.message()),
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::SerdeError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// ========================================
+// State
+// ========================================
+
+/// The directory our state lives in, given the working directory of the local
+/// repository.
+pub fn dir(workdir: &Path) -> PathBuf {
+    workdir.join(".git/homesync")
+}
+
+// Reads the JSON file at the given path, if it exists.
+fn read<T: serde::de::DeserializeOwned + Default>(file: &Path) -> Result<T> {
+    match fs::read_to_string(file) {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e)?,
+    }
+}
+
+fn write<T: serde::Serialize>(file: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(file, serde_json::to_string(value)?)?;
+    Ok(())
+}
+
+// ========================================
+// Hashes
+// ========================================
+
+// A file is assumed unchanged so long as its size and timestamps are. The
+// change time is included since, unlike the modification time, it cannot be
+// set back by restoring metadata.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+struct Stamp {
+    size: u64,
+    mtime: u128,
+    ctime: i128,
+}
+
+impl Stamp {
+    fn of(metadata: &fs::Metadata) -> Self {
+        Stamp {
+            size: metadata.len(),
+            mtime: metadata
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_nanos()),
+            ctime: i128::from(metadata.ctime()) * 1_000_000_000 + i128::from(metadata.ctime_nsec()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Hashed {
+    stamp: Stamp,
+    hash: String,
+}
+
+/// A cache of the content hashes of the files we copy around, so that we only
+/// need to read a file again once it has changed.
+#[derive(Debug)]
+pub struct Hashes {
+    file: PathBuf,
+    entries: BTreeMap<PathBuf, Hashed>,
+    dirty: bool,
+}
+
+impl Hashes {
+    /// Reads the cache of the local repository at the given directory.
+    pub fn load(workdir: &Path) -> Result<Self> {
+        let file = dir(workdir).join("hashes.json");
+        let entries = read(&file)?;
+        Ok(Hashes {
+            file,
+            entries,
+            dirty: false,
+        })
+    }
+
+    /// The git blob id of the contents of the file at the given path (following
+    /// symlinks), or `None` if there is no such file.
+    pub fn hash(&mut self, path: &Path) -> Result<Option<Oid>> {
+        let stamp = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => Stamp::of(&metadata),
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(e)?,
+        };
+        if let Some(hashed) = self.entries.get(path) {
+            if hashed.stamp == stamp {
+                if let Ok(oid) = Oid::from_str(&hashed.hash) {
+                    return Ok(Some(oid));
+                }
+            }
+        }
+        let oid = Oid::hash_file(ObjectType::Blob, path)?;
+        self.entries.insert(
+            path.to_path_buf(),
+            Hashed {
+                stamp,
+                hash: oid.to_string(),
+            },
+        );
+        self.dirty = true;
+        Ok(Some(oid))
+    }
+
+    /// Whether the files at both paths exist and have the same contents.
+    pub fn same(&mut self, a: &Path, b: &Path) -> Result<bool> {
+        Ok(match (self.hash(a)?, self.hash(b)?) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        })
+    }
+
+    /// Writes the cache back out, forgetting about files that no longer exist.
+    pub fn save(&mut self) -> Result<()> {
+        let before = self.entries.len();
+        self.entries.retain(|p, _| p.exists());
+        if self.dirty || self.entries.len() != before {
+            write(&self.file, &self.entries)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+// ========================================
+// Tracked
+// ========================================
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Synced {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base: Option<String>,
+}
+
+/// The managed (unresolved) paths that existed on this machine as of the last
+/// stage or apply. Used to tell a file that was deleted apart from one that
+/// never existed here in the first place.
+///
+/// Each path also remembers its base, i.e. the git blob id of its contents as
+/// last staged or applied, to tell which side changed since.
+#[derive(Debug)]
+pub struct Tracked {
+    file: PathBuf,
+    paths: BTreeMap<PathBuf, Synced>,
+}
+
+impl Tracked {
+    /// Reads the tracked paths of the local repository at the given directory.
+    pub fn load(workdir: &Path) -> Result<Self> {
+        let file = dir(workdir).join("tracked.json");
+        let paths = read(&file)?;
+        Ok(Tracked { file, paths })
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.contains_key(path)
+    }
+
+    pub fn insert(&mut self, path: &Path) {
+        self.paths.entry(path.to_path_buf()).or_default();
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.paths.remove(path);
+    }
+
+    /// The blob id last synced for the given path, if known.
+    pub fn base(&self, path: &Path) -> Option<Oid> {
+        self.paths
+            .get(path)
+            .and_then(|s| s.base.as_ref())
+            .and_then(|b| Oid::from_str(b).ok())
+    }
+
+    /// Tracks the given path, recording its base.
+    pub fn set_base(&mut self, path: &Path, base: Oid) {
+        self.paths.insert(
+            path.to_path_buf(),
+            Synced {
+                base: Some(base.to_string()),
+            },
+        );
+    }
+
+    pub fn save(&self) -> Result<()> {
+        write(&self.file, &self.paths)
+    }
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn hashes() {
+        let temp_dir = TempDir::new().unwrap();
+        let workdir = temp_dir.path();
+        let a = workdir.join("a");
+        let b = workdir.join("b");
+        fs::write(&a, "same").unwrap();
+        fs::write(&b, "same").unwrap();
+
+        let mut hashes = Hashes::load(workdir).unwrap();
+        assert!(hashes.same(&a, &b).unwrap());
+        assert!(!hashes.same(&a, &workdir.join("missing")).unwrap());
+        fs::write(&b, "different").unwrap();
+        assert!(!hashes.same(&a, &b).unwrap());
+        hashes.save().unwrap();
+
+        // Cached hashes are reused until a file changes.
+        let mut hashes = Hashes::load(workdir).unwrap();
+        let expected = Oid::hash_object(ObjectType::Blob, b"same").unwrap();
+        assert_eq!(hashes.hash(&a).unwrap(), Some(expected));
+        assert!(!hashes.dirty);
+        fs::remove_file(&b).unwrap();
+        hashes.save().unwrap();
+        assert_eq!(Hashes::load(workdir).unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn tracked() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut tracked = Tracked::load(temp_dir.path()).unwrap();
+        tracked.insert(Path::new("$HOME/.bashrc"));
+        tracked.insert(Path::new("$HOME/.profile"));
+        tracked.remove(Path::new("$HOME/.profile"));
+        tracked.save().unwrap();
+
+        let mut tracked = Tracked::load(temp_dir.path()).unwrap();
+        assert!(tracked.contains(Path::new("$HOME/.bashrc")));
+        assert!(!tracked.contains(Path::new("$HOME/.profile")));
+        assert_eq!(tracked.base(Path::new("$HOME/.bashrc")), None);
+
+        let base = Oid::hash_object(ObjectType::Blob, b"base").unwrap();
+        tracked.set_base(Path::new("$HOME/.bashrc"), base);
+        tracked.insert(Path::new("$HOME/.bashrc"));
+        assert_eq!(tracked.base(Path::new("$HOME/.bashrc")), Some(base));
+    }
+}
@@ -0,0 +1,153 @@
+//! Storage for files that do not belong in git history.
+//!
+//! Allowed files over the size limit of
+//! [LargeFiles](../config/struct.LargeFiles.html) may be kept in a content
+//! addressed [Store](struct.Store.html) outside of the local repository,
+//! keyed by their git blob id. The local repository then only holds a small
+//! [Pointer](struct.Pointer.html) in their place. Syncing the store itself
+//! across machines (e.g. on a shared drive) is left up to the user.
+
+use git2::Oid;
+use std::{
+    fmt, fs, io,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+// ========================================
+// Pointer
+// ========================================
+
+const HEADER: &str = "homesync pointer";
+
+/// Stands in for a file kept in a [Store](struct.Store.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pointer {
+    pub oid: Oid,
+    pub size: u64,
+}
+
+impl Pointer {
+    /// The pointer held by the file at the given path, or `None` if it is not
+    /// a pointer.
+    pub fn read(path: &Path) -> io::Result<Option<Self>> {
+        match fs::symlink_metadata(path) {
+            // Pointers are tiny, so there is no need to read anything larger.
+            Ok(metadata) if metadata.is_file() && metadata.len() <= 128 => (),
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Pointer::parse(&contents)),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        if lines.next()? != HEADER {
+            return None;
+        }
+        let oid = Oid::from_str(lines.next()?.strip_prefix("oid ")?).ok()?;
+        let size = lines.next()?.strip_prefix("size ")?.parse().ok()?;
+        Some(Pointer { oid, size })
+    }
+}
+
+impl fmt::Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "oid {}", self.oid)?;
+        writeln!(f, "size {}", self.size)
+    }
+}
+
+// ========================================
+// Store
+// ========================================
+
+/// A directory of files named after their git blob ids.
+#[derive(Debug)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(dir: &Path) -> Self {
+        Store {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Where the file with the given blob id is (or would be) kept. Like git,
+    /// objects are spread across subdirectories named after the first two
+    /// characters of their id.
+    pub fn object(&self, oid: Oid) -> PathBuf {
+        let hex = oid.to_string();
+        self.dir.join(&hex[..2]).join(&hex[2..])
+    }
+}
+
+// ========================================
+// Binary Detection
+// ========================================
+
+/// Whether the file at the given path looks binary. Like git, we go by
+/// whether a NUL byte is found within its first 8000 bytes.
+pub fn is_binary(path: &Path) -> io::Result<bool> {
+    let mut buffer = Vec::with_capacity(8000);
+    fs::File::open(path)?.take(8000).read_to_end(&mut buffer)?;
+    Ok(buffer.contains(&0))
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::ObjectType;
+    use tempfile::TempDir;
+
+    #[test]
+    fn pointer() {
+        let temp_dir = TempDir::new().unwrap();
+        let oid = Oid::hash_object(ObjectType::Blob, b"large").unwrap();
+        let pointer = Pointer { oid, size: 5 };
+        let file = temp_dir.path().join("pointer");
+        fs::write(&file, pointer.to_string()).unwrap();
+        assert_eq!(Pointer::read(&file).unwrap(), Some(pointer));
+
+        fs::write(&file, "homesync pointer\noid invalid\nsize 5\n").unwrap();
+        assert_eq!(Pointer::read(&file).unwrap(), None);
+        fs::write(&file, [0xff, 0xfe]).unwrap();
+        assert_eq!(Pointer::read(&file).unwrap(), None);
+        assert_eq!(
+            Pointer::read(&temp_dir.path().join("missing")).unwrap(),
+            None
+        );
+
+        let store = Store::new(temp_dir.path());
+        let object = store.object(oid);
+        assert!(object.starts_with(temp_dir.path().join(&oid.to_string()[..2])));
+    }
+
+    #[test]
+    fn binary() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "plain text\n").unwrap();
+        assert!(!is_binary(&file).unwrap());
+        fs::write(&file, b"\x7fELF\x02\x01\x01\x00").unwrap();
+        assert!(is_binary(&file).unwrap());
+
+        // Only the start of a file is looked at.
+        let mut contents = vec![b'a'; 8000];
+        contents.push(0);
+        fs::write(&file, contents).unwrap();
+        assert!(!is_binary(&file).unwrap());
+    }
+}
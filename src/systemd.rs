@@ -0,0 +1,312 @@
+//! Utilities for running the homesync daemon as a systemd user service.
+//!
+//! Units are written to the systemd user unit directory (i.e.
+//! `$XDG_CONFIG_HOME/systemd/user`, falling back to `$HOME/.config/systemd/user`)
+//! and always point at the currently running binary and the config homesync
+//! was loaded with.
+
+use super::{config::PathConfig, path};
+use simplelog::{info, paris, warn};
+use std::{
+    env,
+    env::VarError,
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    result,
+};
+
+// ========================================
+// Error
+// ========================================
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(io::Error),
+    PathError(path::Error),
+    VarError(VarError),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IOError(err)
+    }
+}
+
+impl From<VarError> for Error {
+    fn from(err: VarError) -> Error {
+        Error::VarError(err)
+    }
+}
+
+impl From<path::Error> for Error {
+    fn from(err: path::Error) -> Error {
+        match err {
+            path::Error::IOError(e) => Error::IOError(e),
+            path::Error::VarError(e) => Error::VarError(e),
+            e => Error::PathError(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::PathError(e) => write!(f, "{}", e),
+            Error::VarError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// ========================================
+// Units
+// ========================================
+
+/// The unit running `homesync daemon`.
+pub const DAEMON_SERVICE: &str = "homesync.service";
+
+/// The oneshot unit periodically syncing with the remote.
+pub const SYNC_SERVICE: &str = "homesync-sync.service";
+
+/// The timer triggering [SYNC_SERVICE](constant.SYNC_SERVICE.html).
+pub const SYNC_TIMER: &str = "homesync-sync.timer";
+
+// systemd splits `ExecStart` on whitespace unless arguments are quoted, and
+// expands `%` specifiers even within quotes.
+fn quote(path: &Path) -> String {
+    format!(
+        "\"{}\"",
+        path.display()
+            .to_string()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%")
+    )
+}
+
+fn render_daemon_service(
+    exe: &Path,
+    config: &Path,
+    freq_secs: u64,
+    commit_on_exit: bool,
+) -> String {
+    format!(
+        "[Unit]\n\
+        Description=Homesync daemon\n\
+        Documentation=https://github.com/jrpotter/homesync\n\
+        \n\
+        [Service]\n\
+        ExecStart={} --config {} daemon --frequency {}{}\n\
+        ExecReload=/bin/kill -HUP $MAINPID\n\
+        Restart=on-failure\n\
+        \n\
+        [Install]\n\
+        WantedBy=default.target\n",
+        quote(exe),
+        quote(config),
+        freq_secs,
+        if commit_on_exit {
+            " --commit-on-exit"
+        } else {
+            ""
+        },
+    )
+}
+
+fn render_sync_service(exe: &Path, config: &Path) -> String {
+    // Pushing always pulls first, so a single invocation syncs both ways.
+    format!(
+        "[Unit]\n\
+        Description=Sync homesync with its remote repository\n\
+        Documentation=https://github.com/jrpotter/homesync\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        ExecStart={} --config {} push\n",
+        quote(exe),
+        quote(config),
+    )
+}
+
+fn render_sync_timer(interval_secs: u64) -> String {
+    format!(
+        "[Unit]\n\
+        Description=Periodically sync homesync with its remote repository\n\
+        \n\
+        [Timer]\n\
+        OnBootSec={interval}s\n\
+        OnUnitActiveSec={interval}s\n\
+        \n\
+        [Install]\n\
+        WantedBy=timers.target\n",
+        interval = interval_secs,
+    )
+}
+
+/// The directory systemd searches for user units.
+pub fn unit_dir() -> Result<PathBuf> {
+    let mut dir = match env::var("XDG_CONFIG_HOME") {
+        Ok(xdg) if !xdg.is_empty() => PathBuf::from(xdg),
+        _ => {
+            let mut home = path::expand(Path::new("$HOME"))?;
+            home.push(".config");
+            home
+        }
+    };
+    dir.push("systemd/user");
+    Ok(dir)
+}
+
+fn write_unit(dir: &Path, name: &str, contents: &str) -> Result<()> {
+    let mut unit = dir.to_path_buf();
+    unit.push(name);
+    fs::write(&unit, contents)?;
+    info!("<bold>Wrote:</> Unit <cyan>{}</>.", unit.display());
+    Ok(())
+}
+
+// ========================================
+// Installation
+// ========================================
+
+/// Writes a systemd user service launching the homesync daemon with the
+/// current binary and config.
+///
+/// If `timer_secs` is specified, a timer is also written that pushes (and
+/// therefore pulls) changes at the given interval.
+pub fn install(
+    pc: &PathConfig,
+    freq_secs: u64,
+    commit_on_exit: bool,
+    timer_secs: Option<u64>,
+) -> Result<()> {
+    let exe = env::current_exe()?;
+    let config = pc.homesync_yml.resolved();
+    let dir = unit_dir()?;
+    fs::create_dir_all(&dir)?;
+
+    write_unit(
+        &dir,
+        DAEMON_SERVICE,
+        &render_daemon_service(&exe, config, freq_secs, commit_on_exit),
+    )?;
+    let mut enable = vec![DAEMON_SERVICE];
+    if let Some(interval) = timer_secs {
+        write_unit(&dir, SYNC_SERVICE, &render_sync_service(&exe, config))?;
+        write_unit(&dir, SYNC_TIMER, &render_sync_timer(interval))?;
+        enable.push(SYNC_TIMER);
+    }
+
+    info!(
+        "<bold>Installed:</> Enable with `<italic>systemctl --user daemon-reload && \
+        systemctl --user enable --now {}</>`.",
+        enable.join(" ")
+    );
+    Ok(())
+}
+
+// The targets each of our units is installed into, i.e. their `WantedBy`.
+const WANTED_BY: [(&str, &str); 2] = [
+    (DAEMON_SERVICE, "default.target"),
+    (SYNC_TIMER, "timers.target"),
+];
+
+// Stops and disables our units so that nothing keeps running (or is started
+// on next login) once their files are gone.
+fn disable(dir: &Path) -> Result<()> {
+    let names: Vec<&str> = WANTED_BY.iter().map(|(name, _)| *name).collect();
+    match Command::new("systemctl")
+        .args(["--user", "disable", "--now"])
+        .args(&names)
+        .status()
+    {
+        Ok(status) if status.success() => {
+            info!("<bold>Disabled:</> Units {}.", names.join(" "));
+        }
+        Ok(status) => warn!("Could not disable units: systemctl exited with {}.", status),
+        Err(e) => warn!("Could not disable units: {}.", e),
+    }
+    // `systemctl` may be unavailable (or fail), in which case the links
+    // enabling our units would be left dangling.
+    for (name, target) in WANTED_BY {
+        let mut link = dir.to_path_buf();
+        link.push(format!("{}.wants", target));
+        link.push(name);
+        match fs::remove_file(&link) {
+            Ok(()) => info!("<bold>Removed:</> Link <cyan>{}</>.", link.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => Err(e)?,
+        }
+    }
+    Ok(())
+}
+
+/// Disables and removes any units previously written by
+/// [install](fn.install.html).
+pub fn uninstall() -> Result<()> {
+    let dir = unit_dir()?;
+    let units: Vec<PathBuf> = [DAEMON_SERVICE, SYNC_SERVICE, SYNC_TIMER]
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|unit| unit.exists())
+        .collect();
+    if units.is_empty() {
+        warn!("No homesync units found in <cyan>{}</>.", dir.display());
+        return Ok(());
+    }
+    disable(&dir)?;
+    for unit in units {
+        match fs::remove_file(&unit) {
+            Ok(()) => info!("<bold>Removed:</> Unit <cyan>{}</>.", unit.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => Err(e)?,
+        }
+    }
+    info!("<bold>Uninstalled:</> Finish with `<italic>systemctl --user daemon-reload</>`.");
+    Ok(())
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daemon_service() {
+        let rendered = render_daemon_service(
+            Path::new("/bin/homesync"),
+            Path::new("/home/owner/my config.yml"),
+            10,
+            false,
+        );
+        assert!(rendered.contains(
+            "ExecStart=\"/bin/homesync\" --config \"/home/owner/my config.yml\" daemon \
+            --frequency 10\n"
+        ));
+        let rendered =
+            render_daemon_service(Path::new("/bin/homesync"), Path::new("/c.yml"), 5, true);
+        assert!(rendered.contains("--frequency 5 --commit-on-exit\n"));
+        let rendered = render_daemon_service(
+            Path::new("/bin/homesync"),
+            Path::new("/home/owner/100%.yml"),
+            5,
+            false,
+        );
+        assert!(rendered.contains("--config \"/home/owner/100%%.yml\""));
+    }
+
+    #[test]
+    fn sync_timer() {
+        let rendered = render_sync_timer(3600);
+        assert!(rendered.contains("OnUnitActiveSec=3600s\n"));
+        assert!(rendered.contains("WantedBy=timers.target\n"));
+    }
+}
@@ -0,0 +1,617 @@
+//! Utilities for rewriting YAML documents without losing their formatting.
+//!
+//! Serializing a value with `serde_yaml` produces a brand new document, losing
+//! any comments, blank lines and key order the user had in place. Instead we
+//! map the original document onto the lines each of its nodes occupy (using
+//! the markers `yaml-rust` reports while parsing) and only regenerate the
+//! entries whose values actually changed.
+
+use serde_yaml::{Mapping, Value};
+use std::{error, fmt, result};
+use yaml_rust::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+// ========================================
+// Error
+// ========================================
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    SerdeError(serde_yaml::Error),
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(err: serde_yaml::Error) -> Error {
+        Error::SerdeError(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::SerdeError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// ========================================
+// Nodes
+// ========================================
+
+// The position of a node within the original document. Lines are 0-indexed.
+#[derive(Debug)]
+enum Node {
+    Scalar {
+        line: usize,
+        value: String,
+    },
+    Sequence {
+        line: usize,
+        col: usize,
+        flow: bool,
+        items: Vec<Node>,
+    },
+    Mapping {
+        line: usize,
+        col: usize,
+        flow: bool,
+        entries: Vec<(String, usize, Node)>,
+    },
+}
+
+impl Node {
+    fn line(&self) -> usize {
+        match self {
+            Node::Scalar { line, .. } => *line,
+            Node::Sequence { line, .. } => *line,
+            Node::Mapping { line, .. } => *line,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Events(Vec<(Event, Marker)>);
+
+impl MarkedEventReceiver for Events {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        self.0.push((ev, mark));
+    }
+}
+
+fn is_flow(lines: &[&str], mark: &Marker) -> bool {
+    lines
+        .get(mark.line() - 1)
+        .and_then(|l| l.chars().nth(mark.col()))
+        .is_some_and(|c| c == '{' || c == '[')
+}
+
+// Builds the node starting at `events[*pos]`. Aliases (and anything else we
+// cannot map back onto lines reliably) yield `None`.
+fn build(events: &[(Event, Marker)], pos: &mut usize, lines: &[&str]) -> Option<Node> {
+    let (event, mark) = events.get(*pos)?;
+    *pos += 1;
+    let line = mark.line() - 1;
+    match event {
+        Event::Scalar(value, ..) => Some(Node::Scalar {
+            line,
+            value: value.clone(),
+        }),
+        Event::SequenceStart(_) => {
+            let mut items = vec![];
+            loop {
+                if let (Event::SequenceEnd, _) = events.get(*pos)? {
+                    *pos += 1;
+                    break;
+                }
+                items.push(build(events, pos, lines)?);
+            }
+            Some(Node::Sequence {
+                line,
+                col: mark.col(),
+                flow: is_flow(lines, mark),
+                items,
+            })
+        }
+        Event::MappingStart(_) => {
+            // The marker of a block mapping does not necessarily point at its
+            // first key, so we take the column from the key instead.
+            let mut col = mark.col();
+            let mut entries = vec![];
+            loop {
+                let (key, key_mark) = match events.get(*pos)? {
+                    (Event::MappingEnd, _) => {
+                        *pos += 1;
+                        break;
+                    }
+                    (Event::Scalar(key, ..), key_mark) => (key.clone(), key_mark),
+                    _ => return None,
+                };
+                *pos += 1;
+                if entries.is_empty() {
+                    col = key_mark.col();
+                }
+                let value = build(events, pos, lines)?;
+                entries.push((key, key_mark.line() - 1, value));
+            }
+            Some(Node::Mapping {
+                line,
+                col,
+                flow: is_flow(lines, mark),
+                entries,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn parse(original: &str, lines: &[&str]) -> Option<Node> {
+    let mut events = Events::default();
+    Parser::new(original.chars())
+        .load(&mut events, false)
+        .ok()?;
+    let start = events
+        .0
+        .iter()
+        .position(|(e, _)| matches!(e, Event::DocumentStart))?;
+    build(&events.0, &mut (start + 1), lines)
+}
+
+// ========================================
+// Reconciliation
+// ========================================
+
+fn is_filler(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    trimmed.is_empty() || trimmed.starts_with('#')
+}
+
+// The end of the content within `[start, limit)`, excluding any trailing
+// comments or blank lines.
+fn content_end(lines: &[&str], start: usize, limit: usize) -> usize {
+    let mut end = limit;
+    while end > start + 1 && is_filler(lines[end - 1]) {
+        end -= 1;
+    }
+    end
+}
+
+// Comments directly above a line are considered part of it.
+fn lead(lines: &[&str], line: usize, floor: usize) -> usize {
+    let mut start = line;
+    while start > floor && is_filler(lines[start - 1]) {
+        start -= 1;
+    }
+    start
+}
+
+// Finds the start of a trailing comment on a line, ignoring any `#` found in
+// quoted strings.
+fn comment_start(line: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' && prev.is_whitespace() => return Some(i),
+            None => (),
+        }
+        prev = c;
+    }
+    None
+}
+
+fn serialize(value: &Value, col: usize) -> Result<Vec<String>> {
+    let serialized = serde_yaml::to_string(value)?;
+    let serialized = serialized.strip_prefix("---\n").unwrap_or(&serialized);
+    Ok(serialized
+        .lines()
+        .map(|l| format!("{}{}", " ".repeat(col), l))
+        .collect())
+}
+
+fn serialize_entry(key: &str, value: &Value, col: usize) -> Result<Vec<String>> {
+    let mut mapping = Mapping::new();
+    mapping.insert(Value::String(key.to_owned()), value.clone());
+    serialize(&Value::Mapping(mapping), col)
+}
+
+// Determines the lines each child occupies, given the line each child starts
+// on. Comments preceding a child belong to it, though never those found
+// before `floor`.
+fn segments(lines: &[&str], starts: &[usize], floor: usize, limit: usize) -> Vec<(usize, usize)> {
+    let mut bounds: Vec<usize> = vec![];
+    for (i, start) in starts.iter().enumerate() {
+        let floor = if i == 0 { floor } else { starts[i - 1] + 1 };
+        bounds.push(lead(lines, *start, floor));
+    }
+    bounds.push(limit);
+    bounds.windows(2).map(|w| (w[0], w[1])).collect()
+}
+
+struct Writer<'a> {
+    lines: &'a [&'a str],
+    out: Vec<String>,
+}
+
+impl<'a> Writer<'a> {
+    fn copy(&mut self, start: usize, end: usize) {
+        self.out
+            .extend(self.lines[start..end].iter().map(|l| l.to_string()));
+    }
+
+    // Writes the value of an entry (or sequence item) that changed, recursing
+    // into block collections so that only the parts that differ are
+    // regenerated. `start` is the line the entry starts on and `end` the end
+    // of its content.
+    fn changed(
+        &mut self,
+        node: &Node,
+        old: &Value,
+        new: &Value,
+        start: usize,
+        end: usize,
+        regenerate: &dyn Fn() -> Result<Vec<String>>,
+    ) -> Result<()> {
+        match (node, old, new) {
+            (
+                Node::Mapping {
+                    line,
+                    col,
+                    flow: false,
+                    entries,
+                },
+                Value::Mapping(old),
+                Value::Mapping(new),
+            ) if *line > start => {
+                self.copy(start, start + 1);
+                self.mapping(entries, old, new, *col, start + 1, end)
+            }
+            (
+                Node::Sequence {
+                    line,
+                    col,
+                    flow: false,
+                    items,
+                },
+                Value::Sequence(old),
+                Value::Sequence(new),
+            ) if *line > start => {
+                self.copy(start, start + 1);
+                self.sequence(items, old, new, *col, start + 1, end)
+            }
+            _ => {
+                let mut generated = regenerate()?;
+                // Keep a trailing comment around if both the old and new
+                // entries fit on a single line.
+                if end == start + 1 && generated.len() == 1 {
+                    if let Some(i) = comment_start(self.lines[start]) {
+                        generated[0] = format!("{} {}", generated[0], &self.lines[start][i..]);
+                    }
+                }
+                self.out.extend(generated);
+                Ok(())
+            }
+        }
+    }
+
+    fn mapping(
+        &mut self,
+        entries: &[(String, usize, Node)],
+        old: &Mapping,
+        new: &Mapping,
+        col: usize,
+        floor: usize,
+        limit: usize,
+    ) -> Result<()> {
+        // New keys are placed after whichever existing key precedes them in
+        // the new mapping, or first if there is none.
+        let mut added: Vec<(Option<&Value>, Mapping)> = vec![(None, Mapping::new())];
+        for (k, nv) in new {
+            if old.contains_key(k) {
+                added.push((Some(k), Mapping::new()));
+            } else if !nv.is_null() {
+                // Missing keys are equivalent to null ones so there is no need
+                // to spell them out.
+                added.last_mut().unwrap().1.insert(k.clone(), nv.clone());
+            }
+        }
+        let write_added = |writer: &mut Self, after: Option<&Value>| -> Result<()> {
+            if let Some((_, mapping)) = added.iter().find(|(k, _)| *k == after) {
+                for (k, nv) in mapping {
+                    match k {
+                        Value::String(key) => writer.out.extend(serialize_entry(key, nv, col)?),
+                        _ => {
+                            let mut mapping = Mapping::new();
+                            mapping.insert(k.clone(), nv.clone());
+                            writer.out.extend(serialize(&Value::Mapping(mapping), col)?);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        };
+
+        let starts: Vec<usize> = entries.iter().map(|(_, line, _)| *line).collect();
+        let segments = segments(self.lines, &starts, floor, limit);
+        write_added(self, None)?;
+        for ((key, line, node), (seg_start, seg_end)) in entries.iter().zip(segments) {
+            let k = Value::String(key.clone());
+            match (old.get(&k), new.get(&k)) {
+                (Some(ov), Some(nv)) if ov == nv => self.copy(seg_start, seg_end),
+                (Some(ov), Some(nv)) => {
+                    let end = content_end(self.lines, *line, seg_end);
+                    self.copy(seg_start, *line);
+                    self.changed(node, ov, nv, *line, end, &|| serialize_entry(key, nv, col))?;
+                    self.copy(end, seg_end);
+                }
+                _ => (),
+            }
+            write_added(self, Some(&k))?;
+        }
+        Ok(())
+    }
+
+    // Sequences are treated as sets: items still present keep their place and
+    // any new items are appended.
+    fn sequence(
+        &mut self,
+        items: &[Node],
+        old: &[Value],
+        new: &[Value],
+        col: usize,
+        floor: usize,
+        limit: usize,
+    ) -> Result<()> {
+        let starts: Vec<usize> = items.iter().map(|n| n.line()).collect();
+        let segments = segments(self.lines, &starts, floor, limit);
+        let mut used = vec![false; new.len()];
+        for (ov, (seg_start, seg_end)) in old.iter().zip(segments) {
+            if let Some(j) = (0..new.len()).find(|j| !used[*j] && new[*j] == *ov) {
+                used[j] = true;
+                self.copy(seg_start, seg_end);
+            }
+        }
+        for (nv, _) in new.iter().zip(used).filter(|(_, u)| !u) {
+            self.out
+                .extend(serialize(&Value::Sequence(vec![nv.clone()]), col)?);
+        }
+        Ok(())
+    }
+}
+
+// Compares two values the way [update](fn.update.html) treats them, i.e.
+// ignoring null entries and the order of sequences.
+fn equivalent(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Mapping(a), Value::Mapping(b)) => {
+            let present = |m: &Mapping| m.iter().filter(|(_, v)| !v.is_null()).count();
+            present(a) == present(b)
+                && a.iter()
+                    .filter(|(_, v)| !v.is_null())
+                    .all(|(k, v)| b.get(k).is_some_and(|w| equivalent(v, w)))
+        }
+        (Value::Sequence(a), Value::Sequence(b)) => {
+            let mut used = vec![false; b.len()];
+            a.len() == b.len()
+                && a.iter().all(|v| {
+                    match (0..b.len()).find(|j| !used[*j] && equivalent(v, &b[*j])) {
+                        Some(j) => {
+                            used[j] = true;
+                            true
+                        }
+                        None => false,
+                    }
+                })
+        }
+        _ => a == b,
+    }
+}
+
+fn reconcile(original: &str, value: &Value) -> Option<Result<String>> {
+    let lines: Vec<&str> = original.lines().collect();
+    let old: Value = serde_yaml::from_str(original).ok()?;
+    let (entries, col, line) = match parse(original, &lines)? {
+        Node::Mapping {
+            entries,
+            col,
+            line,
+            flow: false,
+        } if !entries.is_empty() => (entries, col, line),
+        _ => return None,
+    };
+    let (old, new) = match (&old, value) {
+        (Value::Mapping(old), Value::Mapping(new)) => (old, new),
+        _ => return None,
+    };
+
+    let limit = content_end(&lines, line, lines.len());
+    let mut writer = Writer {
+        lines: &lines,
+        out: vec![],
+    };
+    let floor = lead(&lines, line, 0);
+    writer.copy(0, floor);
+    if let Err(e) = writer.mapping(&entries, old, new, col, floor, limit) {
+        return Some(Err(e));
+    }
+    writer.copy(limit, lines.len());
+
+    let mut updated = writer.out.join("\n");
+    updated.push('\n');
+    // Never trade correctness for formatting.
+    match serde_yaml::from_str::<Value>(&updated) {
+        Ok(v) if equivalent(&v, value) => Some(Ok(updated)),
+        _ => None,
+    }
+}
+
+/// Produces a YAML document representing `value`, keeping as much of the
+/// `original` document's comments, blank lines and ordering as possible.
+///
+/// Falls back to a plain serialization of `value` if the original document
+/// cannot be mapped onto it, e.g. if it does not parse or is not a mapping.
+pub fn update(original: &str, value: &Value) -> Result<String> {
+    match reconcile(original, value) {
+        Some(updated) => updated,
+        None => Ok(serde_yaml::to_string(value)?),
+    }
+}
+
+// ========================================
+// Location
+// ========================================
+
+/// A step taken from one node of a YAML document to one of its children.
+#[derive(Clone, Copy, Debug)]
+pub enum Step<'a> {
+    /// The value of the entry with the given key in a mapping.
+    Key(&'a str),
+    /// The item at the given index of a sequence.
+    Index(usize),
+    /// The first scalar item of a sequence equal to the given string.
+    Item(&'a str),
+}
+
+/// Finds the (1-indexed) line the node found by following `steps` from the
+/// root of the document starts on. For mapping entries, this is the line of
+/// the key.
+pub fn locate(original: &str, steps: &[Step]) -> Option<usize> {
+    let lines: Vec<&str> = original.lines().collect();
+    let mut node = parse(original, &lines)?;
+    let mut line = node.line();
+    for step in steps {
+        node = match (step, node) {
+            (Step::Key(key), Node::Mapping { entries, .. }) => {
+                let (_, key_line, value) = entries.into_iter().find(|(k, _, _)| k == key)?;
+                line = key_line;
+                value
+            }
+            (Step::Index(index), Node::Sequence { items, .. }) => {
+                let item = items.into_iter().nth(*index)?;
+                line = item.line();
+                item
+            }
+            (Step::Item(value), Node::Sequence { items, .. }) => {
+                let item = items
+                    .into_iter()
+                    .find(|n| matches!(n, Node::Scalar { value: v, .. } if v == value))?;
+                line = item.line();
+                item
+            }
+            _ => return None,
+        };
+    }
+    Some(line + 1)
+}
+
+// ========================================
+// Tests
+// ========================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "\
+---
+# Who commits are attributed to.
+user:
+  name: name  # Full name.
+  email: email@email.com
+
+unmanaged:
+  - LICENSE
+  - README.md
+packages:
+  # Shell configuration.
+  shell:
+  - $HOME/.bashrc  # Login shell.
+  - $HOME/.profile
+
+  homesync:
+    - $HOME/.homesync.yml
+# Trailing comment.
+";
+
+    fn edit(f: impl FnOnce(&mut Value)) -> String {
+        let mut value: Value = serde_yaml::from_str(ORIGINAL).unwrap();
+        f(&mut value);
+        update(ORIGINAL, &value).unwrap()
+    }
+
+    #[test]
+    fn unchanged() {
+        assert_eq!(edit(|_| ()), ORIGINAL);
+    }
+
+    #[test]
+    fn scalar_changed() {
+        let updated = edit(|v| v["user"]["name"] = Value::from("other"));
+        assert_eq!(
+            updated,
+            ORIGINAL.replace("  name: name  # Full name.", "  name: other # Full name.")
+        );
+    }
+
+    #[test]
+    fn sequence_changed() {
+        let updated = edit(|v| {
+            let shell = v["packages"]["shell"].as_sequence_mut().unwrap();
+            shell.remove(1);
+            shell.push(Value::from("$HOME/.inputrc"));
+        });
+        assert_eq!(
+            updated,
+            ORIGINAL.replace("  - $HOME/.profile\n", "  - $HOME/.inputrc\n")
+        );
+    }
+
+    #[test]
+    fn entry_removed_and_added() {
+        let updated = edit(|v| {
+            let packages = v["packages"].as_mapping_mut().unwrap();
+            packages.remove(&Value::from("shell"));
+            packages.insert(
+                Value::from("vim"),
+                Value::Sequence(vec![Value::from("$HOME/.vimrc")]),
+            );
+        });
+        let value: Value = serde_yaml::from_str(&updated).unwrap();
+        assert_eq!(value["packages"]["vim"][0], "$HOME/.vimrc");
+        assert!(!updated.contains("shell"));
+        assert!(!updated.contains("# Shell configuration."));
+        assert!(updated.contains("# Who commits are attributed to."));
+        assert!(updated.ends_with("# Trailing comment.\n"));
+    }
+
+    #[test]
+    fn locate() {
+        assert_eq!(super::locate(ORIGINAL, &[Step::Key("user")]), Some(3));
+        assert_eq!(
+            super::locate(
+                ORIGINAL,
+                &[Step::Key("packages"), Step::Key("shell"), Step::Index(1)]
+            ),
+            Some(14)
+        );
+        assert_eq!(
+            super::locate(ORIGINAL, &[Step::Key("unmanaged"), Step::Item("README.md")]),
+            Some(9)
+        );
+        assert_eq!(super::locate(ORIGINAL, &[Step::Key("missing")]), None);
+    }
+
+    #[test]
+    fn fallback() {
+        let value: Value = serde_yaml::from_str("a: 1").unwrap();
+        assert_eq!(update("- not a mapping", &value).unwrap(), "---\na: 1\n");
+    }
+}